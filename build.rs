@@ -6,8 +6,28 @@ fn main() {
     // let ld = &out.join("rustsbi-prototyper.ld");
     let ld = &out.join("linker.ld");
 
+    // S 态 payload 由 OpenSBI/RustSBI 跳转到 0x80200000，M 态固件仍放在 0x80400000；
+    // QEMU virt 用 -bios none 启动时没有前级引导，直接从内存起点 0x80000000 开始执行
+    let base = if env::var_os("CARGO_FEATURE_SUPERVISOR").is_some() {
+        "0x80200000"
+    } else if env::var_os("CARGO_FEATURE_QEMU").is_some() {
+        "0x80000000"
+    } else {
+        "0x80400000"
+    };
+
+    // 只有 alloc feature 才需要堆，否则不占这 1 MiB；end_heap 仍然要定义，PMP 用它作为可写区域的末尾
+    let heap = if env::var_os("CARGO_FEATURE_ALLOC").is_some() {
+        HEAP_SECTION
+    } else {
+        "end_heap = ALIGN(8);"
+    };
+
     // 将LINKER_SCRIPT 内容写入 $OUT_DIR/rustsbi-prototyper.ld
-    std::fs::write(ld, LINKER_SCRIPT).unwrap();
+    let script = LINKER_SCRIPT
+        .replace("${BASE}", base)
+        .replace("${HEAP}", heap);
+    std::fs::write(ld, script).unwrap();
 
     // 如果这些环境变量变化，会重新运行构建脚本
     // println!("cargo:rerun-if-env-changed=RUST_LOG,PROTOTYPER_FDT,PROTOTYPER_IMAGE");
@@ -16,32 +36,49 @@ fn main() {
     //
     // println!("cargo:rustc-link-arg=-nostartfiles");
     println!("cargo:rustc-link-arg=-T{}", ld.display());
+    // defmt 的格式字符串表放在 defmt.x 定义的 .defmt 段里
+    if env::var_os("CARGO_FEATURE_DEFMT").is_some() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
+
+    // payload 模式下可以通过 PAYLOAD 环境变量把 S 态镜像嵌进固件
+    println!("cargo:rerun-if-env-changed=PAYLOAD");
+    println!("cargo:rustc-check-cfg=cfg(payload_image)");
+    if env::var_os("PAYLOAD").is_some() {
+        println!("cargo:rustc-cfg=payload_image");
+    }
     println!("cargo:rustc-link-search={}", out.display());
 }
 
 // OUTPUT_ARCH(riscv)：指定目标架构为 RISC-V
 // ENTRY(_start)：程序入口点为 _start
-// . = 0x80000000：代码加载地址（RISC-V 常见的启动地址）
+// . = ${BASE}：代码加载地址，M 态为 0x80400000（QEMU 为 0x80000000），S 态 payload 为 0x80200000
 // .text：代码段（.text.entry 是启动代码，后面是其他代码）
 // .rodata：只读数据段
 // .data：可读写数据段
 // .bss：未初始化数据段（堆栈、堆等）
+// stack_guard：栈底下方 4 KiB 保护区，有 PMP 时设为禁止访问
+// .noinit：启动时不清零，热复位后保留内容（复位原因等）
+// .heap：全局堆（1 MiB），只在开启 alloc feature 时放进 ${HEAP}
 // .text 0x80200000：payload 段（U-Boot 或其他内核）的加载地址
 // *(.section_name) 会收集所有标记为该段的目标代码
 // Rust的#[link_section]就是将函数/数据放入指定段的标准方法
-const LINKER_SCRIPT: &[u8] = b"OUTPUT_ARCH(riscv)
+const LINKER_SCRIPT: &str = "OUTPUT_ARCH(riscv)
 ENTRY(_start)
 
 SECTIONS {
-    . = 0x80400000;
+    . = ${BASE};
 
     .text : { 
+        start_text = .;
         *(.text.entry)
         *(.text .text.*)
+        end_text = .;
     }
     .rodata : ALIGN(0x1000)  {
         *(.rodata .rodata.*)
         *(.srodata .srodata.*)
+        end_rodata = .;
     }
 
     .data : ALIGN(0x1000)  {
@@ -49,6 +86,9 @@ SECTIONS {
         *(.sdata .sdata.*)
     }
     .bss (NOLOAD) : ALIGN(0x1000) {
+        stack_guard_start = .;
+        . += 0x1000;
+        stack_guard_end = .;
         *(.bss.stack)
         start_bss = .;
         *(.bss .bss.*)
@@ -56,12 +96,24 @@ SECTIONS {
         end_bss = .;
     }
 
+    .noinit (NOLOAD) : ALIGN(8) {
+        *(.noinit .noinit.*)
+    }
+
+    ${HEAP}
+
     /DISCARD/ : {
         *(.eh_frame)
         *(.debug*)
     }
 }";
 
+const HEAP_SECTION: &str = ".heap (NOLOAD) : ALIGN(0x1000) {
+        start_heap = .;
+        . += 0x100000;
+        end_heap = .;
+    }";
+
 // .bss : {
 //     start_bss = .;
 //     *(.bss.stack)
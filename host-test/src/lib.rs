@@ -0,0 +1,31 @@
+//! 固件驱动和控制台等模块的主机测试
+//!
+//! 固件本身只能交叉编译，这里用 `#[path]` 直接引入源码，驱动里 `#[cfg(test)]`
+//! 的测试用软件寄存器模型代替 MMIO，和平台相关的初始化代码在测试时不编译。
+#![allow(dead_code)]
+// 和固件 crate 一样允许引用 static mut
+#![allow(static_mut_refs)]
+
+#[cfg(test)]
+#[path = "../../src/i2c.rs"]
+mod i2c;
+
+#[cfg(test)]
+#[path = "../../src/log.rs"]
+mod log;
+
+#[cfg(test)]
+#[path = "../../src/monitor.rs"]
+mod monitor;
+
+#[cfg(test)]
+#[path = "../../src/ramlog.rs"]
+mod ramlog;
+
+#[cfg(test)]
+#[path = "../../src/spi.rs"]
+mod spi;
+
+#[cfg(test)]
+#[path = "../../src/tx_ring.rs"]
+mod tx_ring;
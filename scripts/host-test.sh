@@ -0,0 +1,10 @@
+#!/bin/sh
+# 在主机上运行驱动的单元测试
+#
+# 仓库的 .cargo/config.toml 指定了 RISC-V 目标和 build-std，cargo 按当前目录查找配置，
+# 所以换到仓库外面的目录再用 --manifest-path 运行。
+set -eu
+
+root=$(cd "$(dirname "$0")/.." && pwd)
+cd "${TMPDIR:-/tmp}"
+exec cargo test --manifest-path "$root/host-test/Cargo.toml" "$@"
@@ -0,0 +1,57 @@
+#!/bin/sh
+# 在 qemu-system-riscv32 上启动 `qemu` 配置，检查串口上有没有启动信息
+#
+# 用法：scripts/qemu-rv32.sh [--defmt] [秒数]
+#
+# 启动后命令行会一直运行，到时间由 timeout 结束 QEMU。串口输出保存在
+# target/qemu-rv32.log，其中有 "embassy_app <版本>" 时返回 0，否则打印日志并返回 1。
+# `--defmt` 同时打开 defmt feature，并用 defmt-print（`cargo install defmt-print`）
+# 解码串口上的 defmt 帧，结果存到 target/qemu-rv32.defmt.log，解码失败时返回 1。
+# `println!` 的文本每段后面跟着帧分隔符，defmt-print 把它们当成单独的坏帧丢掉，
+# 文本只在原始日志里看。
+set -eu
+
+features=qemu
+defmt=
+if [ "${1:-}" = --defmt ]; then
+    features=qemu,defmt
+    defmt=1
+    shift
+fi
+
+root=$(cd "$(dirname "$0")/.." && pwd)
+cd "$root"
+seconds=${1:-10}
+elf=target/riscv32imc/release/embassy_app
+log=target/qemu-rv32.log
+version=$(sed -n 's/^version = "\(.*\)"/\1/p' Cargo.toml | head -n 1)
+
+for tool in qemu-system-riscv32 ${defmt:+defmt-print}; do
+    if ! command -v "$tool" >/dev/null; then
+        echo "qemu-rv32: $tool not found" >&2
+        exit 1
+    fi
+done
+
+cargo build -Z build-std --release --target riscv32imc.json --features "$features"
+
+# 标准输入接 /dev/null，QEMU 不会等终端；被 timeout 结束时返回 124，不算失败
+# QEMU 自己的提示走 stderr，不能混进串口输出
+timeout "$seconds" qemu-system-riscv32 -machine virt -nographic -bios none \
+    -kernel "$elf" </dev/null >"$log" 2>target/qemu-rv32.stderr || [ $? -eq 124 ]
+
+if [ -n "$defmt" ]; then
+    if ! defmt-print -e "$elf" <"$log" >target/qemu-rv32.defmt.log; then
+        echo "qemu-rv32: defmt-print failed to decode the serial output" >&2
+        exit 1
+    fi
+    cat target/qemu-rv32.defmt.log
+fi
+
+if grep -q "embassy_app $version" "$log"; then
+    echo "qemu-rv32: banner found, serial output in $log"
+else
+    echo "qemu-rv32: \"embassy_app $version\" not found on serial:" >&2
+    cat "$log" >&2
+    exit 1
+fi
@@ -0,0 +1,58 @@
+//! 启动阶段跟踪
+//!
+//! `_start` 里每完成一步调用一次 [`stage`]，记下阶段名和当时的 mtime。
+//! 执行器启动后 [`report`] 打印每个阶段的耗时；启动中途 panic 时 panic handler
+//! 用 [`last_stage`] 报告最后完成的阶段，方便定位卡在哪一步。
+//!
+//! 只在启动 hart 上、执行器启动之前调用，不需要加锁。
+
+use crate::time_driver;
+
+/// 最多记录的阶段数，超出的阶段只更新 [`last_stage`]
+const MAX_STAGES: usize = 24;
+
+static mut STAGES: [(&str, u64); MAX_STAGES] = [("", 0); MAX_STAGES];
+static mut COUNT: usize = 0;
+static mut LAST: Option<&str> = None;
+
+/// 记录一个已经完成的阶段
+pub fn stage(name: &'static str) {
+    let now = time_driver::mtime();
+    unsafe {
+        if COUNT < MAX_STAGES {
+            STAGES[COUNT] = (name, now);
+            COUNT += 1;
+        }
+        LAST = Some(name);
+    }
+}
+
+/// 最后完成的阶段，还没有记录时为 `None`
+pub fn last_stage() -> Option<&'static str> {
+    unsafe { LAST }
+}
+
+/// 打印每个阶段的完成时间（mtime 从复位开始计数，包含前级引导的时间）和距上一阶段的耗时
+pub fn report() {
+    let hz = time_driver::mtime_hz().max(1);
+    let us = |ticks: u64| ticks * 1_000_000 / hz;
+    let stages = unsafe { &STAGES[..COUNT] };
+    let Some(&(_, first)) = stages.first() else {
+        return;
+    };
+    println!(
+        "boot:    {:<16} {:>10} {:>10}",
+        "stage", "at(us)", "took(us)"
+    );
+    let mut previous = first;
+    for &(name, at) in stages {
+        println!(
+            "boot:    {:<16} {:>10} {:>10}",
+            name,
+            us(at),
+            us(at - previous)
+        );
+        previous = at;
+    }
+    println!("boot:    total {} us", us(previous - first));
+}
@@ -0,0 +1,221 @@
+//! JH7110 时钟和复位控制器（SYSCRG / AONCRG / STGCRG）
+//!
+//! 时钟寄存器按编号排列在 `基地址 + id * 4`，格式见 Linux clk-starfive-jh71x0：
+//! bit31 使能，bit24~29 选择父时钟，bit0~23 分频系数。复位寄存器每一位对应一个复位，
+//! 写 1 保持复位，状态寄存器读到 1 表示已经释放。
+//! 时钟和复位编号与 Linux dt-bindings `starfive,jh7110-crg.h` 一致。
+
+/// 外部晶振
+pub const OSC_HZ: u64 = 24_000_000;
+/// PLL2 输出，U-Boot SPL 配置的默认值，没有从 syscon 读回
+pub const PLL2_HZ: u64 = 1_188_000_000;
+
+// SYSCRG 时钟编号
+pub const SYSCLK_BUS_ROOT: u32 = 5;
+pub const SYSCLK_AXI_CFG0: u32 = 7;
+pub const SYSCLK_STG_AXIAHB: u32 = 8;
+pub const SYSCLK_APB_BUS: u32 = 11;
+pub const SYSCLK_APB0: u32 = 12;
+/// CLINT mtime 的计数时钟
+pub const SYSCLK_RTC_TOGGLE: u32 = 31;
+pub const SYSCLK_PWM_APB: u32 = 121;
+pub const SYSCLK_WDT_APB: u32 = 122;
+pub const SYSCLK_WDT_CORE: u32 = 123;
+pub const SYSCLK_SPI0_APB: u32 = 131;
+pub const SYSCLK_SPI6_APB: u32 = 137;
+pub const SYSCLK_I2C0_APB: u32 = 138;
+pub const SYSCLK_I2C6_APB: u32 = 144;
+pub const SYSCLK_UART0_APB: u32 = 145;
+pub const SYSCLK_UART0_CORE: u32 = 146;
+
+// SYSCRG 复位编号
+pub const SYSRST_SPI0_APB: u32 = 69;
+pub const SYSRST_I2C0_APB: u32 = 76;
+pub const SYSRST_UART0_APB: u32 = 83;
+pub const SYSRST_UART0_CORE: u32 = 84;
+pub const SYSRST_PWM_APB: u32 = 108;
+pub const SYSRST_WDT_APB: u32 = 109;
+pub const SYSRST_WDT_CORE: u32 = 110;
+
+const CLK_ENABLE: u32 = 1 << 31;
+const CLK_MUX_SHIFT: u32 = 24;
+const CLK_MUX_MASK: u32 = 0x3f << CLK_MUX_SHIFT;
+const CLK_DIV_MASK: u32 = 0xff_ffff;
+
+// 等待复位释放的最大轮询次数
+const RESET_POLL: usize = 100_000;
+
+/// 时钟和复位控制器
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Crg {
+    Sys,
+    Aon,
+    Stg,
+}
+
+impl Crg {
+    pub const fn base(self) -> usize {
+        match self {
+            Crg::Sys => 0x1302_0000,
+            Crg::Aon => 0x1700_0000,
+            Crg::Stg => 0x1023_0000,
+        }
+    }
+
+    // (复位寄存器, 复位状态寄存器) 的偏移
+    const fn reset_regs(self) -> (usize, usize) {
+        match self {
+            Crg::Sys => (0x2f8, 0x308),
+            Crg::Aon => (0x38, 0x3c),
+            Crg::Stg => (0x74, 0x78),
+        }
+    }
+
+    fn clk_reg(self, id: u32) -> *mut u32 {
+        (self.base() + id as usize * 4) as *mut u32
+    }
+}
+
+/// 等待复位释放超时
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ResetTimeout {
+    pub crg: Crg,
+    pub id: u32,
+}
+
+fn modify(addr: *mut u32, clr_mask: u32, set_mask: u32) {
+    unsafe { addr.write_volatile((addr.read_volatile() & !clr_mask) | set_mask) };
+}
+
+/// 打开时钟门控
+pub fn enable(crg: Crg, id: u32) {
+    modify(crg.clk_reg(id), 0, CLK_ENABLE);
+}
+
+/// 关闭时钟门控
+pub fn disable(crg: Crg, id: u32) {
+    modify(crg.clk_reg(id), CLK_ENABLE, 0);
+}
+
+pub fn is_enabled(crg: Crg, id: u32) -> bool {
+    unsafe { crg.clk_reg(id).read_volatile() & CLK_ENABLE != 0 }
+}
+
+/// 设置分频系数，只对带分频的时钟有效
+pub fn set_divider(crg: Crg, id: u32, div: u32) {
+    assert!(div != 0 && div <= CLK_DIV_MASK, "invalid divider {}", div);
+    modify(crg.clk_reg(id), CLK_DIV_MASK, div);
+}
+
+pub fn divider(crg: Crg, id: u32) -> u32 {
+    unsafe { crg.clk_reg(id).read_volatile() & CLK_DIV_MASK }
+}
+
+/// 选择父时钟，`index` 为父时钟列表里的序号
+pub fn set_parent(crg: Crg, id: u32, index: u32) {
+    modify(
+        crg.clk_reg(id),
+        CLK_MUX_MASK,
+        (index << CLK_MUX_SHIFT) & CLK_MUX_MASK,
+    );
+}
+
+pub fn parent(crg: Crg, id: u32) -> u32 {
+    unsafe { (crg.clk_reg(id).read_volatile() & CLK_MUX_MASK) >> CLK_MUX_SHIFT }
+}
+
+fn reset_bit(crg: Crg, offset: usize, id: u32) -> (*mut u32, u32) {
+    let addr = (crg.base() + offset + (id as usize / 32) * 4) as *mut u32;
+    (addr, 1 << (id % 32))
+}
+
+/// 让外设进入复位
+pub fn assert_reset(crg: Crg, id: u32) {
+    let (addr, mask) = reset_bit(crg, crg.reset_regs().0, id);
+    modify(addr, 0, mask);
+}
+
+/// 释放复位并等待状态寄存器确认
+pub fn deassert_reset(crg: Crg, id: u32) -> Result<(), ResetTimeout> {
+    let (assert, status) = crg.reset_regs();
+    let (addr, mask) = reset_bit(crg, assert, id);
+    modify(addr, mask, 0);
+    let (status, _) = reset_bit(crg, status, id);
+    for _ in 0..RESET_POLL {
+        if unsafe { status.read_volatile() } & mask != 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(ResetTimeout { crg, id })
+}
+
+/// 驱动初始化时调用：先打开所有时钟，再依次释放复位
+pub fn bring_up(crg: Crg, clocks: &[u32], resets: &[u32]) -> Result<(), ResetTimeout> {
+    clocks.iter().for_each(|&id| enable(crg, id));
+    resets.iter().try_for_each(|&id| deassert_reset(crg, id))
+}
+
+#[derive(Clone, Copy)]
+enum Parent {
+    Osc,
+    Pll2,
+    Clock(u32),
+    Mux(&'static [Parent]),
+}
+
+// SYSCRG 时钟树里外设驱动用到的部分：(父时钟, 是否带分频)
+fn sys_node(id: u32) -> Option<(Parent, bool)> {
+    Some(match id {
+        SYSCLK_BUS_ROOT => (Parent::Mux(&[Parent::Osc, Parent::Pll2]), false),
+        SYSCLK_AXI_CFG0 => (Parent::Clock(SYSCLK_BUS_ROOT), true),
+        SYSCLK_STG_AXIAHB => (Parent::Clock(SYSCLK_AXI_CFG0), true),
+        SYSCLK_APB_BUS => (Parent::Clock(SYSCLK_STG_AXIAHB), true),
+        SYSCLK_APB0 => (Parent::Clock(SYSCLK_APB_BUS), false),
+        SYSCLK_RTC_TOGGLE => (Parent::Osc, true),
+        SYSCLK_PWM_APB | SYSCLK_WDT_APB | SYSCLK_UART0_APB => (Parent::Clock(SYSCLK_APB0), false),
+        SYSCLK_SPI0_APB..=SYSCLK_SPI6_APB | SYSCLK_I2C0_APB..=SYSCLK_I2C6_APB => {
+            (Parent::Clock(SYSCLK_APB0), false)
+        }
+        SYSCLK_WDT_CORE | SYSCLK_UART0_CORE => (Parent::Osc, false),
+        _ => return None,
+    })
+}
+
+fn parent_rate(id: u32, parent: Parent) -> Option<u64> {
+    match parent {
+        Parent::Osc => Some(OSC_HZ),
+        Parent::Pll2 => Some(PLL2_HZ),
+        Parent::Clock(parent) => sys_rate(parent),
+        Parent::Mux(parents) => parent_rate(id, *parents.get(self::parent(Crg::Sys, id) as usize)?),
+    }
+}
+
+/// SYSCRG 时钟的当前频率，不在时钟树表里的返回 `None`
+pub fn sys_rate(id: u32) -> Option<u64> {
+    let (parent, has_div) = sys_node(id)?;
+    let rate = parent_rate(id, parent)?;
+    if has_div {
+        // 分频寄存器为 0 时时钟不输出
+        match divider(Crg::Sys, id) {
+            0 => Some(0),
+            div => Some(rate / div as u64),
+        }
+    } else {
+        Some(rate)
+    }
+}
+
+/// QEMU virt 的 timebase-frequency
+#[cfg(feature = "qemu")]
+pub const QEMU_TIMEBASE_HZ: u64 = 10_000_000;
+
+/// CLINT mtime 的实际计数频率
+pub fn clint_rate() -> u64 {
+    #[cfg(feature = "qemu")]
+    return QEMU_TIMEBASE_HZ;
+    #[cfg(not(feature = "qemu"))]
+    sys_rate(SYSCLK_RTC_TOGGLE).unwrap_or(0)
+}
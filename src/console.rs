@@ -1,9 +1,22 @@
-use core::fmt;
+use core::{
+    cell::Cell,
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+use critical_section::Mutex;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+// 目标没有 CAS，计数用 portable-atomic
+use portable_atomic::AtomicUsize;
+use static_cell::StaticCell;
 
 // use bouffalo_hal::uart::RegisterBlock as BflbUartRegisterBlock;
 // use uart_xilinx::MmioUartAxiLite;
 use uart16550::{Register, Uart16550};
 
+use crate::{ramlog, tx_ring::TxRing};
+
 // use crate::sbi::console::ConsoleDevice;
 pub(crate) const UART16650U8_COMPATIBLE: [&str; 1] = ["ns16550a"];
 pub(crate) const UART16650U32_COMPATIBLE: [&str; 1] = ["snps,dw-apb-uart"];
@@ -20,29 +33,357 @@ pub enum MachineConsoleType {
     UartBflb,
 }
 
-pub struct Platform {
-    pub console: Option<Uart16550Wrap<u32>>,
+/// M 态直接驱动 UART，S 态通过 SBI 输出
+#[cfg(all(feature = "machine", not(feature = "qemu")))]
+pub type PlatformConsole = Uart16550Wrap<u32>;
+/// QEMU virt 的 ns16550a 寄存器间隔为 1 字节
+#[cfg(all(feature = "machine", feature = "qemu"))]
+pub type PlatformConsole = Uart16550Wrap<u8>;
+#[cfg(feature = "supervisor")]
+pub type PlatformConsole = crate::supervisor::SbiConsole;
+
+static CONSOLE: StaticCell<PlatformConsole> = StaticCell::new();
+// 指向 [`CONSOLE`]，只在 [`init`] 里写一次，之后只读
+static PLATFORM: Mutex<Cell<Option<&'static PlatformConsole>>> = Mutex::new(Cell::new(None));
+
+/// 设置控制台，并把启动早期缓存的输出发出去，只能调用一次
+pub fn init(console: PlatformConsole) {
+    let console = CONSOLE.init(console);
+    critical_section::with(|cs| PLATFORM.borrow(cs).set(Some(console)));
+    drain();
+}
+
+/// 当前的控制台，还没调用 [`init`] 时为 `None`
+pub fn get() -> Option<&'static PlatformConsole> {
+    critical_section::with(|cs| PLATFORM.borrow(cs).get())
+}
+
+/// 读取控制台输入，返回实际读到的字节数，不阻塞
+pub fn read(buf: &mut [u8]) -> usize {
+    get().map_or(0, |console| console.read(buf))
+}
+
+// 打印和 UART 之间的发送环形缓冲区
+//
+// `print!` 只把数据放进缓冲区，由 [`drain_task`] 在后台写到 UART，不再等 UART 把每个
+// 字节都发出去。缓冲区不加锁：写入方先通过 `reserve` 占一段空间，拷贝完再推进
+// `commit`，读出方只发 `commit` 之前的数据，每次只把 UART FIFO 能接收的部分搬过去。
+// drain_task 还没运行时（启动阶段）缓冲区满了总是等待，不会丢掉启动日志；
+// 控制台还没设置时先存在缓冲区里，满了就丢掉。panic 时改为同步直接输出。
+// Block 只在中断打开时原地等待，中断和临界区里不能忙等 UART，按 Drop 处理。
+// Overwrite 会推进读出方正在发送的数据的 tail，读出方先把一段拷到栈上，确认 tail
+// 没变才发送，发出去的总是完整的旧数据。
+
+/// 发送缓冲区大小，必须是 2 的幂，见 [`TxRing`]
+const TX_BUFFER_SIZE: usize = 4096;
+/// 读出方每次从缓冲区拷出的最大字节数
+const DRAIN_CHUNK: usize = 64;
+/// `print!` 在栈上攒一行的缓冲区大小，更长的行分几次放进发送缓冲区
+const LINE_BUFFER_SIZE: usize = 128;
+/// UART FIFO 满时后台任务的重试间隔
+const DRAIN_RETRY: Duration = Duration::from_micros(200);
+
+/// 发送缓冲区满时的处理方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OverflowPolicy {
+    /// 丢掉放不下的新数据，计入 [`dropped`]
+    Drop = 0,
+    /// 原地等 UART 腾出空间
+    Block = 1,
+    /// 丢掉最旧的数据
+    Overwrite = 2,
+}
+
+static TX: TxRing<TX_BUFFER_SIZE> = TxRing::new();
+static POLICY: AtomicU8 = AtomicU8::new(OverflowPolicy::Drop as u8);
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static DRAINING: AtomicBool = AtomicBool::new(false);
+// 打开中断之前在启动 hart 上顺序执行，Block 可以关着中断等待
+static BOOTING: AtomicBool = AtomicBool::new(true);
+static PANICKING: AtomicBool = AtomicBool::new(false);
+static TX_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// 设置缓冲区满时的处理方式
+pub fn set_overflow_policy(policy: OverflowPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// 因缓冲区满被丢掉的字节数
+pub fn dropped() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// 启动代码打开中断前调用，之后关中断时（中断处理函数、临界区里）不再原地等待
+pub fn boot_done() {
+    BOOTING.store(false, Ordering::Relaxed);
+}
+
+// 是否可以原地等 UART：启动阶段，或者中断是打开的（不在中断处理函数和临界区里）
+fn may_block() -> bool {
+    if BOOTING.load(Ordering::Relaxed) {
+        return true;
+    }
+    #[cfg(feature = "machine")]
+    return riscv::register::mstatus::read().mie();
+    #[cfg(feature = "supervisor")]
+    return riscv::register::sstatus::read().sie();
+}
+
+fn policy() -> OverflowPolicy {
+    if !DRAINING.load(Ordering::Relaxed) {
+        return OverflowPolicy::Block;
+    }
+    match POLICY.load(Ordering::Relaxed) {
+        1 => OverflowPolicy::Block,
+        2 => OverflowPolicy::Overwrite,
+        _ => OverflowPolicy::Drop,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Drain {
+    /// 已经写完的数据都交给了 UART
+    Empty,
+    /// UART FIFO 满了，还有数据没发
+    Pending,
+    /// 没有控制台，或者打断了另一个读出方
+    Busy,
+}
+
+// 把缓冲区里的数据搬到 UART，直到缓冲区空或者 UART 不再接收
+fn drain() -> Drain {
+    let Some(console) = get() else {
+        return Drain::Busy;
+    };
+    if !TX.acquire_reader() {
+        return Drain::Busy;
+    }
+    let mut chunk = [0u8; DRAIN_CHUNK];
+    let result = loop {
+        let Some((tail, len)) = TX.peek(&mut chunk) else {
+            break Drain::Empty;
+        };
+        let count = console.write(&chunk[..len]);
+        if count == 0 {
+            break Drain::Pending;
+        }
+        TX.consume(tail, count);
+    };
+    TX.release_reader();
+    result
+}
+
+// 同步写到 UART，只在 panic 之后使用
+fn write_direct(mut bytes: &[u8]) {
+    let Some(console) = get() else {
+        return;
+    };
+    while !bytes.is_empty() {
+        let count = console.write(bytes);
+        bytes = &bytes[count..];
+    }
 }
 
-impl Platform {
+/// 把一段文本放进发送缓冲区，同时记进 ramlog
+///
+/// 开启 `defmt` 时文本和 defmt 帧共用一个串口，文本后面补一个 0 字节（rzcobs 的帧分隔符），
+/// `defmt-print` 把它当成一整个坏帧丢掉，不会和后面的帧粘在一起把帧也弄坏。
+/// 文本本身不能编码成 defmt 帧：JH7110 上固件在 0x80400000，medany 代码模型
+/// 够不到放在地址 0 附近的 defmt 字符串表，任何 `defmt::println!` 都链接不过。
+pub fn write_bytes(bytes: &[u8]) {
+    // 上电保留的日志要维护校验和，每次（通常是一行）在一个很短的临界区里拷贝
+    critical_section::with(|_| ramlog::write(bytes));
+    #[cfg(not(feature = "defmt"))]
+    write_raw(bytes);
+    // 分隔符和文本要一起放进去，中间插进来的帧会和文本粘在一起
+    #[cfg(feature = "defmt")]
+    for chunk in bytes.chunks(LINE_BUFFER_SIZE) {
+        let mut buf = [0u8; LINE_BUFFER_SIZE + 1];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        write_raw(&buf[..chunk.len() + 1]);
+    }
+}
+
+/// 把 defmt 编码好的帧放进发送缓冲区，不记进 ramlog，ramlog 里只有文本
+#[cfg(feature = "defmt")]
+pub fn write_frame(bytes: &[u8]) {
+    write_raw(bytes);
+}
+
+fn write_raw(mut bytes: &[u8]) {
+    if PANICKING.load(Ordering::Relaxed) {
+        write_direct(bytes);
+        return;
+    }
+    let policy = policy();
+    while !bytes.is_empty() {
+        let (count, discarded) = TX.push(bytes, policy == OverflowPolicy::Overwrite);
+        DROPPED.fetch_add(discarded, Ordering::Relaxed);
+        if count == 0 {
+            // 只有 Drop 和 Block 会放不下。Block 时原地发送腾出空间；关着中断、没有控制台、
+            // 打断了读出方、或者空间都被打断的写入方占着时等不到，只能丢掉
+            if policy == OverflowPolicy::Block && may_block() {
+                match drain() {
+                    Drain::Pending => continue,
+                    Drain::Empty if TX.free() != 0 => continue,
+                    _ => {}
+                }
+            }
+            DROPPED.fetch_add(bytes.len(), Ordering::Relaxed);
+            break;
+        }
+        bytes = &bytes[count..];
+    }
+    // UART FIFO 有空就先发一部分，剩下的交给后台任务
+    if drain() != Drain::Empty {
+        TX_SIGNAL.signal(());
+    }
+}
+
+/// panic 时调用：同步发完缓冲区里的数据，之后的打印都直接写 UART
+pub fn panic_flush() {
+    PANICKING.store(true, Ordering::Relaxed);
+    // panic 可能发生在读出方被打断的时候，它不会再回来了
+    TX.release_reader();
+    flush();
+}
+
+/// 同步发完缓冲区里的数据，没有控制台或者打断了后台发送时直接返回
+pub fn flush() {
+    while drain() == Drain::Pending {
+        core::hint::spin_loop();
+    }
+}
+
+/// 后台把发送缓冲区写到 UART 的任务
+#[embassy_executor::task]
+pub async fn drain_task() {
+    DRAINING.store(true, Ordering::Relaxed);
+    loop {
+        if drain() == Drain::Empty {
+            TX_SIGNAL.wait().await;
+        } else {
+            Timer::after(DRAIN_RETRY).await;
+        }
+    }
+}
+
+/// 在栈上攒满一行再放进发送缓冲区的 `fmt::Write`
+pub struct Writer {
+    buf: [u8; LINE_BUFFER_SIZE],
+    len: usize,
+}
+
+impl Writer {
     pub const fn new() -> Self {
-        Self { console: None }
+        Self {
+            buf: [0; LINE_BUFFER_SIZE],
+            len: 0,
+        }
+    }
+
+    /// 把攒下的数据放进发送缓冲区
+    pub fn flush(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        write_bytes(&self.buf[..self.len]);
+        self.len = 0;
     }
 }
 
-pub static mut PLATFORM: Platform = Platform::new();
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            if self.len == LINE_BUFFER_SIZE {
+                self.flush();
+            }
+            let len = bytes.len().min(LINE_BUFFER_SIZE - self.len);
+            self.buf[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+            self.len += len;
+            bytes = &bytes[len..];
+        }
+        Ok(())
+    }
+}
+
+// 绕过缓冲区同步写 UART 的 `fmt::Write`
+struct DirectWriter;
+
+impl fmt::Write for DirectWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_direct(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// `print!`/`println!` 的实现
+///
+/// 先在栈上格式化整行，再一次放进缓冲区，中断里的打印不会插到行的中间
+/// （超过 [`LINE_BUFFER_SIZE`] 的行除外）。格式化期间不关中断。
+/// 格式化出错（某个 `Display` 实现返回错误）时只丢掉这一行剩下的部分。
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments, newline: bool) {
+    let mut writer = Writer::new();
+    writer.write_fmt(args).ok();
+    if newline {
+        writer.write_str("\n\r").ok();
+    }
+    writer.flush();
+}
+
+/// `eprint!`/`eprintln!` 的实现
+///
+/// 先发完缓冲区里已有的数据再同步写出，返回时已经交给 UART，适合随后就可能卡死的场合。
+/// 还没有控制台时和 `print!` 一样先存进缓冲区。开启 `defmt` 时文本要带上帧分隔符，
+/// 同样先放进缓冲区再同步发完。
+#[doc(hidden)]
+pub fn _eprint(args: fmt::Arguments, newline: bool) {
+    if cfg!(feature = "defmt") {
+        _print(args, newline);
+        flush();
+        return;
+    }
+    if get().is_none() {
+        return _print(args, newline);
+    }
+    critical_section::with(|_| {
+        flush();
+        let mut writer = DirectWriter;
+        writer.write_fmt(args).ok();
+        if newline {
+            writer.write_str("\n\r").ok();
+        }
+    })
+}
 
 /// For Uart 16550
 pub struct Uart16550Wrap<R: Register> {
     inner: *const Uart16550<R>,
 }
 
+// 只是寄存器的地址，UART 本身就是全局共享的外设
+unsafe impl<R: Register> Send for Uart16550Wrap<R> {}
+unsafe impl<R: Register> Sync for Uart16550Wrap<R> {}
+
 impl<R: Register> Uart16550Wrap<R> {
     pub fn new(base: usize) -> Self {
         Self {
             inner: base as *const Uart16550<R>,
         }
     }
+
+    /// 从接收 FIFO 读取，返回实际读到的字节数，不阻塞
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        unsafe { (*self.inner).read(buf) }
+    }
+
+    /// 写入发送 FIFO，返回实际写入的字节数，不阻塞
+    pub fn write(&self, buf: &[u8]) -> usize {
+        unsafe { (*self.inner).write(buf) }
+    }
 }
 
 impl<R: Register> fmt::Write for Uart16550Wrap<R> {
@@ -0,0 +1,49 @@
+//! defmt 全局日志器，把 rzcobs 编码的帧写进控制台的发送缓冲区
+//!
+//! 整个帧在临界区里写入，中断里打印的日志不会插进半个帧。主机端用
+//! `defmt-print -e <elf>` 从串口读取并解码，见 README。
+
+use crate::console;
+
+#[defmt::global_logger]
+struct UartLogger;
+
+static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+static mut TAKEN: bool = false;
+static mut RESTORE: critical_section::RestoreState = critical_section::RestoreState::invalid();
+
+defmt::timestamp!("{=u64:us}", embassy_time::Instant::now().as_micros());
+
+fn write_all(bytes: &[u8]) {
+    console::write_frame(bytes);
+}
+
+unsafe impl defmt::Logger for UartLogger {
+    fn acquire() {
+        let restore = unsafe { critical_section::acquire() };
+        unsafe {
+            if TAKEN {
+                panic!("defmt logger taken reentrantly");
+            }
+            TAKEN = true;
+            RESTORE = restore;
+            ENCODER.start_frame(write_all);
+        }
+    }
+
+    unsafe fn flush() {
+        console::flush();
+    }
+
+    unsafe fn release() {
+        unsafe {
+            ENCODER.end_frame(write_all);
+            TAKEN = false;
+            critical_section::release(RESTORE);
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        unsafe { ENCODER.write(bytes, write_all) };
+    }
+}
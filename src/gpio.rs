@@ -1,4 +1,8 @@
 // 详见u-boot/arch/riscv/include/asm/arch-jh7110/gpio.h
+use core::{convert::Infallible, marker::PhantomData};
+
+use embedded_hal::digital::{ErrorType, InputPin, OutputPin, StatefulOutputPin};
+
 // GPIO 控制器基地址
 pub const GPIO_BASE: usize = 0x13040000;
 const GPIOA_BASE: usize = 0x17020000;
@@ -7,13 +11,48 @@ const GPIOA_BASE: usize = 0x17020000;
 const GPIO_DOEN: usize = 0x0;
 const GPIO_DOUT: usize = 0x40;
 const GPIO_DIN: usize = 0x80;
+// 管脚实际电平，每个 bit 对应一个 GPIO（见 Linux pinctrl-starfive-jh7110-sys）
+const GPIO_GPIOIN: usize = 0x118;
 const GPIO_CONFIG: usize = 0x120;
 
+// GPIO 数量
+const NR_GPIOS: u32 = 64;
+
 // 掩码定义
 const GPIO_DOEN_MASK: u32 = 0x3f;
 const GPIO_DOUT_MASK: u32 = 0x7f;
 const GPIO_DIN_MASK: u32 = 0x7f;
 
+// 管脚配置寄存器（GPIO_CONFIG + gpio * 4）各位
+const GPIO_IE: u32 = 1 << 0;
+const GPIO_DS_MASK: u32 = 0x06;
+const GPIO_DS_SHIFT: u32 = 1;
+const GPIO_PULL_MASK: u32 = 0x18;
+const GPIO_PULL_SHIFT: u32 = 3;
+const GPIO_PULL_UP: u32 = 1;
+const GPIO_PULL_DOWN: u32 = 2;
+const GPIO_SLEW_MASK: u32 = 1 << 5;
+const GPIO_SLEW_SHIFT: u32 = 5;
+const GPIO_SMT: u32 = 1 << 6;
+const GPIO_PAD_MASK: u32 = GPIO_IE | GPIO_DS_MASK | GPIO_PULL_MASK | GPIO_SLEW_MASK | GPIO_SMT;
+
+// AON GPIO（RGPIO0~3）的寄存器，见 Linux pinctrl-starfive-jh7110-aon
+const AON_NR_GPIOS: u32 = 4;
+const AON_DOEN: usize = 0x0;
+const AON_DOUT: usize = 0x4;
+const AON_DIN: usize = 0x8;
+const AON_CONFIG: usize = 0x30;
+// AON 的管脚配置寄存器从 TESTEN 开始，RGPIO0 是第 1 个
+const AON_PAD_FIRST_GPIO: u32 = 1;
+const AON_DOEN_MASK: u32 = 0x7;
+const AON_DOUT_MASK: u32 = 0xf;
+const AON_DIN_MASK: u32 = 0xf;
+
+// 固定电平和输出使能的信号编号，外设信号的完整列表见 Linux dt-bindings/pinctrl/starfive,jh7110-pinctrl.h
+pub const GPOUT_LOW: u32 = 0;
+pub const GPOEN_ENABLE: u32 = 0;
+pub const GPOEN_DISABLE: u32 = 1;
+
 // 辅助宏的 Rust 实现
 fn gpio_offset(gpio: u32) -> usize {
     ((gpio >> 2) << 2) as usize
@@ -47,12 +86,283 @@ unsafe fn sys_iomux_dout(gpio_base: usize, gpio: u32, gpo: u32) {
     );
 }
 
-fn sys_iomux_din_read(gpio_base: usize, gpio: u32) -> bool {
-    let addr = (gpio_base + GPIO_DIN + ((gpio >> 5) * 4) as usize) as *mut u32;
+// 读回 DOUT 寄存器里该 GPIO 的输出选择，1 表示输出高电平
+fn sys_iomux_dout_read(gpio_base: usize, gpio: u32) -> u32 {
+    let addr = (gpio_base + GPIO_DOUT + gpio_offset(gpio)) as *const u32;
+    let value = unsafe { addr.read_volatile() };
+    (value >> gpio_shift(gpio)) & GPIO_DOUT_MASK
+}
+
+// 读取管脚的实际输入电平
+fn sys_gpioin_read(gpio_base: usize, gpio: u32) -> bool {
+    let addr = (gpio_base + GPIO_GPIOIN + ((gpio >> 5) * 4) as usize) as *const u32;
     let value = unsafe { addr.read_volatile() };
     ((value >> (gpio & 0x1F)) & 0x1) != 0
 }
 
+// 把管脚连到外设输入信号 gpi（SYS_IOMUX_DIN），寄存器按 gpi 编址，写入值为 gpio + 2
+unsafe fn sys_iomux_din(gpio_base: usize, gpio: u32, gpi: u32) {
+    let addr = (gpio_base + GPIO_DIN + gpio_offset(gpi)) as *mut u32;
+    let shift = gpio_shift(gpi);
+    clrsetbits_le32(
+        addr,
+        GPIO_DIN_MASK << shift,
+        ((gpio + 2) & GPIO_DIN_MASK) << shift,
+    );
+}
+
+// 修改管脚配置寄存器
+unsafe fn sys_pad_config(gpio_base: usize, gpio: u32, clr_mask: u32, set_mask: u32) {
+    let addr = (gpio_base + GPIO_CONFIG + (gpio as usize) * 4) as *mut u32;
+    clrsetbits_le32(addr, clr_mask, set_mask);
+}
+
+/// 输入管脚的上下拉
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Pull {
+    #[default]
+    None,
+    Up,
+    Down,
+}
+
+/// 输入管脚配置
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InputConfig {
+    pub pull: Pull,
+    /// 施密特触发，按键之类的慢边沿信号建议打开
+    pub schmitt: bool,
+}
+
+impl InputConfig {
+    pub const fn new() -> Self {
+        Self {
+            pull: Pull::None,
+            schmitt: false,
+        }
+    }
+
+    pub const fn pull(mut self, pull: Pull) -> Self {
+        self.pull = pull;
+        self
+    }
+
+    pub const fn schmitt(mut self, schmitt: bool) -> Self {
+        self.schmitt = schmitt;
+        self
+    }
+}
+
+/// GPIO 所在的控制器
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Bank {
+    /// SYS GPIO0~63
+    Sys,
+    /// AON RGPIO0~3
+    Aon,
+}
+
+impl Bank {
+    pub const fn base(self) -> usize {
+        match self {
+            Bank::Sys => GPIO_BASE,
+            Bank::Aon => GPIOA_BASE,
+        }
+    }
+
+    pub const fn gpios(self) -> u32 {
+        match self {
+            Bank::Sys => NR_GPIOS,
+            Bank::Aon => AON_NR_GPIOS,
+        }
+    }
+
+    // (寄存器偏移, 掩码)，DOEN、DOUT、DIN 的排布和 SYS 一样每个管脚占 8 位
+    const fn doen(self) -> (usize, u32) {
+        match self {
+            Bank::Sys => (GPIO_DOEN, GPIO_DOEN_MASK),
+            Bank::Aon => (AON_DOEN, AON_DOEN_MASK),
+        }
+    }
+
+    const fn dout(self) -> (usize, u32) {
+        match self {
+            Bank::Sys => (GPIO_DOUT, GPIO_DOUT_MASK),
+            Bank::Aon => (AON_DOUT, AON_DOUT_MASK),
+        }
+    }
+
+    const fn din(self) -> (usize, u32) {
+        match self {
+            Bank::Sys => (GPIO_DIN, GPIO_DIN_MASK),
+            Bank::Aon => (AON_DIN, AON_DIN_MASK),
+        }
+    }
+
+    const fn config(self, gpio: u32) -> *mut u32 {
+        let addr = match self {
+            Bank::Sys => GPIO_BASE + GPIO_CONFIG + (gpio as usize) * 4,
+            Bank::Aon => GPIOA_BASE + AON_CONFIG + ((gpio + AON_PAD_FIRST_GPIO) as usize) * 4,
+        };
+        addr as *mut u32
+    }
+}
+
+// 写 DOEN / DOUT / DIN 中 index 对应的 8 位
+fn iomux_write(bank: Bank, (reg, mask): (usize, u32), index: u32, value: u32) {
+    let addr = (bank.base() + reg + gpio_offset(index)) as *mut u32;
+    let shift = gpio_shift(index);
+    clrsetbits_le32(addr, mask << shift, (value & mask) << shift);
+}
+
+/// 驱动能力
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DriveStrength {
+    #[default]
+    Ma2 = 0,
+    Ma4 = 1,
+    Ma8 = 2,
+    Ma12 = 3,
+}
+
+/// 输出边沿速率
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Slew {
+    #[default]
+    Slow = 0,
+    Fast = 1,
+}
+
+/// 管脚电气配置，对应 GPIO_CONFIG 寄存器
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PadConfig {
+    pub drive: DriveStrength,
+    pub pull: Pull,
+    pub slew: Slew,
+    /// 输入使能，关掉后读到的电平和输入中断都无效
+    pub input_enable: bool,
+    pub schmitt: bool,
+}
+
+impl PadConfig {
+    pub const fn new() -> Self {
+        Self {
+            drive: DriveStrength::Ma2,
+            pull: Pull::None,
+            slew: Slew::Slow,
+            input_enable: true,
+            schmitt: false,
+        }
+    }
+
+    pub const fn drive(mut self, drive: DriveStrength) -> Self {
+        self.drive = drive;
+        self
+    }
+
+    pub const fn pull(mut self, pull: Pull) -> Self {
+        self.pull = pull;
+        self
+    }
+
+    pub const fn slew(mut self, slew: Slew) -> Self {
+        self.slew = slew;
+        self
+    }
+
+    pub const fn input_enable(mut self, input_enable: bool) -> Self {
+        self.input_enable = input_enable;
+        self
+    }
+
+    pub const fn schmitt(mut self, schmitt: bool) -> Self {
+        self.schmitt = schmitt;
+        self
+    }
+
+    const fn bits(&self) -> u32 {
+        let pull = match self.pull {
+            Pull::None => 0,
+            Pull::Up => GPIO_PULL_UP,
+            Pull::Down => GPIO_PULL_DOWN,
+        };
+        let mut bits = ((self.drive as u32) << GPIO_DS_SHIFT)
+            | (pull << GPIO_PULL_SHIFT)
+            | ((self.slew as u32) << GPIO_SLEW_SHIFT);
+        if self.input_enable {
+            bits |= GPIO_IE;
+        }
+        if self.schmitt {
+            bits |= GPIO_SMT;
+        }
+        bits
+    }
+}
+
+/// 管脚上的外设信号，相当于 U-Boot 的 `SYS_IOMUX_COMPLEX`
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Function {
+    /// 输出信号，GPOUT_*
+    pub dout: u32,
+    /// 输出使能信号，GPOEN_*
+    pub doen: u32,
+    /// 从该管脚取输入的外设信号，GPI_*
+    pub din: Option<u32>,
+}
+
+impl Function {
+    /// 只输出，例如 UART TX
+    pub const fn output(dout: u32) -> Self {
+        Self {
+            dout,
+            doen: GPOEN_ENABLE,
+            din: None,
+        }
+    }
+
+    /// 只输入，例如 UART RX
+    pub const fn input(din: u32) -> Self {
+        Self {
+            dout: GPOUT_LOW,
+            doen: GPOEN_DISABLE,
+            din: Some(din),
+        }
+    }
+
+    /// 双向信号，例如 I2C 的 SCL/SDA 由外设控制输出使能
+    pub const fn bidirectional(dout: u32, doen: u32, din: u32) -> Self {
+        Self {
+            dout,
+            doen,
+            din: Some(din),
+        }
+    }
+}
+
+/// 写管脚的电气配置
+pub fn set_pad_config(bank: Bank, gpio: u32, config: PadConfig) {
+    assert!(gpio < bank.gpios(), "invalid gpio {}", gpio);
+    clrsetbits_le32(bank.config(gpio), GPIO_PAD_MASK, config.bits());
+}
+
+/// 把外设信号接到管脚上
+pub fn set_function(bank: Bank, gpio: u32, function: Function) {
+    assert!(gpio < bank.gpios(), "invalid gpio {}", gpio);
+    iomux_write(bank, bank.doen(), gpio, function.doen);
+    iomux_write(bank, bank.dout(), gpio, function.dout);
+    // DIN 按输入信号编址，写入值为 gpio + 2（0 和 1 表示固定低/高电平）
+    if let Some(gpi) = function.din {
+        iomux_write(bank, bank.din(), gpi, gpio + 2);
+    }
+}
+
 // 高级封装函数
 pub fn init_gpio_as_output(gpio_base: usize, gpio: u32) {
     // 设置为输出模式 (oen = 0)
@@ -61,16 +371,176 @@ pub fn init_gpio_as_output(gpio_base: usize, gpio: u32) {
     unsafe { sys_iomux_dout(gpio_base, gpio, 0) };
 }
 
+// 设置为输入：关闭输出驱动，打开管脚输入使能并配置上下拉和施密特触发
+pub fn init_gpio_as_input(gpio_base: usize, gpio: u32, config: InputConfig) {
+    // 关闭输出 (oen = 1)
+    unsafe { sys_iomux_doen(gpio_base, gpio, 1) };
+    let pull = match config.pull {
+        Pull::None => 0,
+        Pull::Up => GPIO_PULL_UP,
+        Pull::Down => GPIO_PULL_DOWN,
+    };
+    let mut set = GPIO_IE | (pull << GPIO_PULL_SHIFT);
+    if config.schmitt {
+        set |= GPIO_SMT;
+    }
+    unsafe { sys_pad_config(gpio_base, gpio, GPIO_PULL_MASK | GPIO_SMT, set) };
+}
+
+// 把输入管脚同时送给外设的输入信号 gpi，例如 UART RX
+pub fn connect_gpio_input(gpio_base: usize, gpio: u32, gpi: u32) {
+    unsafe { sys_iomux_din(gpio_base, gpio, gpi) };
+}
+
+// 读取管脚当前电平
+pub fn read_gpio(gpio_base: usize, gpio: u32) -> bool {
+    sys_gpioin_read(gpio_base, gpio)
+}
+
 pub fn set_gpio_output(gpio_base: usize, gpio: u32, high: bool) {
     let value = if high { 1 } else { 0 };
     unsafe { sys_iomux_dout(gpio_base, gpio, value) };
 }
 
-pub unsafe fn toggle_gpio(gpio_base: usize, gpio: u32) {
-    static mut GPIO_STATES: [bool; 64] = [false; 64];
+// 读回 DOUT 寄存器的当前输出再取反，不再维护影子数组
+pub fn toggle_gpio(gpio_base: usize, gpio: u32) {
+    if gpio < NR_GPIOS {
+        let high = sys_iomux_dout_read(gpio_base, gpio) == 1;
+        set_gpio_output(gpio_base, gpio, !high);
+    }
+}
+
+// 类型状态的 GPIO 管脚 API，实现 embedded-hal 1.0 的数字 IO trait
+
+/// 输入模式
+pub struct Input;
+/// 输出模式
+pub struct Output;
+/// 输出关闭、输入不使用
+pub struct Disabled;
+/// 交给外设使用
+pub struct Alternate;
+
+/// SYS GPIO 外设单例，用来分配各个管脚
+pub struct Gpio {
+    taken: u64,
+}
+
+static mut GPIO_TAKEN: bool = false;
+
+impl Gpio {
+    /// 获取 GPIO 外设，只有第一次调用返回 `Some`
+    pub fn take() -> Option<Self> {
+        critical_section::with(|_| unsafe {
+            if GPIO_TAKEN {
+                None
+            } else {
+                GPIO_TAKEN = true;
+                Some(Self { taken: 0 })
+            }
+        })
+    }
+
+    /// 取出第 `N` 个管脚，同一个管脚只能取一次
+    pub fn pin<const N: u32>(&mut self) -> Option<Pin<N, Disabled>> {
+        const { assert!(N < NR_GPIOS) };
+        if self.taken & (1 << N) != 0 {
+            return None;
+        }
+        self.taken |= 1 << N;
+        Some(Pin { _mode: PhantomData })
+    }
+}
+
+/// 第 `N` 个 GPIO 管脚，`MODE` 为当前模式
+pub struct Pin<const N: u32, MODE> {
+    _mode: PhantomData<MODE>,
+}
+
+impl<const N: u32, MODE> Pin<N, MODE> {
+    /// 切换为输出，初始输出低电平
+    pub fn into_output(self) -> Pin<N, Output> {
+        init_gpio_as_output(GPIO_BASE, N);
+        Pin { _mode: PhantomData }
+    }
+
+    /// 切换为输入，不带上下拉
+    pub fn into_input(self) -> Pin<N, Input> {
+        self.into_input_with(InputConfig::new())
+    }
+
+    /// 按 `config` 切换为输入
+    pub fn into_input_with(self, config: InputConfig) -> Pin<N, Input> {
+        init_gpio_as_input(GPIO_BASE, N, config);
+        Pin { _mode: PhantomData }
+    }
+
+    /// 关闭输出并把输出选择清零
+    pub fn into_disabled(self) -> Pin<N, Disabled> {
+        unsafe {
+            sys_iomux_doen(GPIO_BASE, N, 1);
+            sys_iomux_dout(GPIO_BASE, N, 0);
+        }
+        Pin { _mode: PhantomData }
+    }
+
+    /// 把管脚交给外设信号
+    pub fn into_function(self, function: Function) -> Pin<N, Alternate> {
+        set_function(Bank::Sys, N, function);
+        Pin { _mode: PhantomData }
+    }
+
+    /// 修改驱动能力、上下拉等电气配置，不改变模式
+    pub fn set_pad_config(&mut self, config: PadConfig) {
+        set_pad_config(Bank::Sys, N, config);
+    }
+
+    /// 管脚编号
+    pub const fn number(&self) -> u32 {
+        N
+    }
+}
+
+impl<const N: u32, MODE> ErrorType for Pin<N, MODE> {
+    type Error = Infallible;
+}
+
+impl<const N: u32> OutputPin for Pin<N, Output> {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        set_gpio_output(GPIO_BASE, N, false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        set_gpio_output(GPIO_BASE, N, true);
+        Ok(())
+    }
+}
+
+impl<const N: u32> StatefulOutputPin for Pin<N, Output> {
+    // 直接读回 DOUT，toggle 用默认实现
+    fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(sys_iomux_dout_read(GPIO_BASE, N) == 1)
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(sys_iomux_dout_read(GPIO_BASE, N) == 0)
+    }
+}
+
+impl<const N: u32> Pin<N, Input> {
+    /// 读取当前电平
+    pub fn read(&self) -> bool {
+        read_gpio(GPIO_BASE, N)
+    }
+}
+
+impl<const N: u32> InputPin for Pin<N, Input> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.read())
+    }
 
-    if gpio < 64 {
-        GPIO_STATES[gpio as usize] = !GPIO_STATES[gpio as usize];
-        set_gpio_output(gpio_base, gpio, GPIO_STATES[gpio as usize]);
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.read())
     }
 }
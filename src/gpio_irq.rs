@@ -0,0 +1,166 @@
+//! SYS GPIO 中断，给输入管脚提供异步的边沿/电平等待
+//!
+//! 寄存器定义见 Linux pinctrl-starfive-jh7110：IS 选择边沿/电平，IBE 选择双边沿，
+//! IEV 选择上升沿/高电平，IE 为 1 时打开，IC 先清 0 再置 1 清除中断。
+//! GPIO 中断经 PLIC 的 86 号中断源进来，中断里关掉触发的那一位并唤醒等待的任务。
+
+use core::{
+    future::{Future, poll_fn},
+    task::Poll,
+};
+
+use embassy_sync::waitqueue::AtomicWaker;
+use embedded_hal_async::digital::Wait;
+use portable_atomic::{AtomicU64, Ordering};
+
+use crate::{
+    gpio::{GPIO_BASE, Input, Pin, read_gpio},
+    plic,
+};
+
+/// SYS GPIO 在 PLIC 上的中断号
+pub const SYS_GPIO_IRQ: usize = 86;
+
+const GPIO_EN: usize = 0xdc;
+const GPIO_IS: usize = 0xe0;
+const GPIO_IC: usize = 0xe8;
+const GPIO_IBE: usize = 0xf0;
+const GPIO_IEV: usize = 0xf8;
+const GPIO_IE: usize = 0x100;
+const GPIO_MIS: usize = 0x110;
+
+const NR_GPIOS: usize = 64;
+
+static WAKERS: [AtomicWaker; NR_GPIOS] = [const { AtomicWaker::new() }; NR_GPIOS];
+/// 已经触发、还没被等待方取走的 GPIO
+static FIRED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Trigger {
+    RisingEdge,
+    FallingEdge,
+    AnyEdge,
+    High,
+    Low,
+}
+
+fn bank_reg(offset: usize, gpio: u32) -> (*mut u32, u32) {
+    let addr = (GPIO_BASE + offset + (gpio as usize / 32) * 4) as *mut u32;
+    (addr, 1 << (gpio % 32))
+}
+
+fn write_bit(offset: usize, gpio: u32, set: bool) {
+    let (addr, mask) = bank_reg(offset, gpio);
+    unsafe {
+        let value = addr.read_volatile();
+        addr.write_volatile(if set { value | mask } else { value & !mask });
+    }
+}
+
+fn clear_interrupt(gpio: u32) {
+    write_bit(GPIO_IC, gpio, false);
+    write_bit(GPIO_IC, gpio, true);
+}
+
+/// 打开 GPIO 中断总开关并挂到 PLIC 上
+pub fn init() {
+    for bank in 0..2 {
+        unsafe { ((GPIO_BASE + GPIO_IE + bank * 4) as *mut u32).write_volatile(0) };
+    }
+    unsafe { ((GPIO_BASE + GPIO_EN) as *mut u32).write_volatile(1) };
+    plic::register(SYS_GPIO_IRQ, on_interrupt);
+}
+
+fn on_interrupt() {
+    for bank in 0..2u32 {
+        let status = unsafe {
+            ((GPIO_BASE + GPIO_MIS) as *const u32)
+                .add(bank as usize)
+                .read_volatile()
+        };
+        for bit in 0..32 {
+            if status & (1 << bit) == 0 {
+                continue;
+            }
+            let gpio = bank * 32 + bit;
+            // 电平触发会一直触发，先关掉，等下一次等待时再打开
+            write_bit(GPIO_IE, gpio, false);
+            clear_interrupt(gpio);
+            FIRED.fetch_or(1 << gpio, Ordering::Relaxed);
+            WAKERS[gpio as usize].wake();
+        }
+    }
+}
+
+fn arm(gpio: u32, trigger: Trigger) {
+    let (edge, both, polarity) = match trigger {
+        Trigger::RisingEdge => (true, false, true),
+        Trigger::FallingEdge => (true, false, false),
+        Trigger::AnyEdge => (true, true, false),
+        Trigger::High => (false, false, true),
+        Trigger::Low => (false, false, false),
+    };
+    critical_section::with(|_| {
+        write_bit(GPIO_IE, gpio, false);
+        write_bit(GPIO_IS, gpio, edge);
+        write_bit(GPIO_IBE, gpio, both);
+        write_bit(GPIO_IEV, gpio, polarity);
+        clear_interrupt(gpio);
+        FIRED.fetch_and(!(1 << gpio), Ordering::Relaxed);
+        write_bit(GPIO_IE, gpio, true);
+    });
+}
+
+/// 等待过程中被取消时关掉中断
+struct Disarm(u32);
+
+impl Drop for Disarm {
+    fn drop(&mut self) {
+        write_bit(GPIO_IE, self.0, false);
+    }
+}
+
+fn wait_for(gpio: u32, trigger: Trigger) -> impl Future<Output = ()> {
+    arm(gpio, trigger);
+    let guard = Disarm(gpio);
+    poll_fn(move |cx| {
+        let _ = &guard;
+        WAKERS[gpio as usize].register(cx.waker());
+        if FIRED.fetch_and(!(1 << gpio), Ordering::Relaxed) & (1 << gpio) != 0 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+}
+
+impl<const N: u32> Wait for Pin<N, Input> {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        if !read_gpio(GPIO_BASE, N) {
+            wait_for(N, Trigger::High).await;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        if read_gpio(GPIO_BASE, N) {
+            wait_for(N, Trigger::Low).await;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        wait_for(N, Trigger::RisingEdge).await;
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        wait_for(N, Trigger::FallingEdge).await;
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        wait_for(N, Trigger::AnyEdge).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+//! M 态陷入处理：S 态 payload 的 SBI 调用以及 M 态软件中断
+//!
+//! 只管理启动 hart 一个核，HSM 对其它 hart 返回参数错误；IPI、RFENCE 也都在本地完成。
+
+use core::arch::asm;
+
+use fast_trap::{FastContext, FastResult};
+use riscv::register::{marchid, mepc, mimpid, mip, mvendorid};
+
+use crate::{
+    CLINT, console, payload, pmp,
+    sbi::{self, SbiRet},
+};
+
+/// 实现编号，SBI 规范里 0..=10 已分配，这里随便取一个未使用的值
+const IMPL_ID: usize = 0xeb;
+const IMPL_VERSION: usize = 1;
+/// SBI 规范 2.0
+const SPEC_VERSION: usize = 2 << 24;
+
+/// `send_ipi` 是否有待转发给 S 态的软件中断
+static mut IPI_PENDING: bool = false;
+
+/// M 态软件中断：清 msip，转发 S 态的 IPI，然后轮询 Embassy 执行器
+pub fn msoft_handler(mut ctx: FastContext) -> FastResult {
+    unsafe { CLINT.clear_msip(payload::boot_hart()) };
+    if unsafe { core::mem::take(&mut IPI_PENDING) } {
+        unsafe { mip::set_ssoft() };
+    }
+    payload::poll_executor();
+    ctx.restore()
+}
+
+pub fn sbi_call_handler(
+    mut ctx: FastContext,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
+    a5: usize,
+    a6: usize,
+    a7: usize,
+) -> FastResult {
+    let a0 = ctx.a0();
+    let ret = match a7 {
+        sbi::EID_BASE => base(a6, a0),
+        sbi::EID_TIME if a6 == sbi::TIME_SET_TIMER => set_timer(a0, a1),
+        sbi::EID_IPI if a6 == sbi::IPI_SEND_IPI => send_ipi(a0, a1),
+        sbi::EID_RFENCE => rfence(a6),
+        sbi::EID_HSM => hsm(a6, a0),
+        sbi::EID_DBCN => dbcn(a6, a0, a1, a2),
+        sbi::EID_LEGACY_SET_TIMER => set_timer(a0, a1),
+        sbi::EID_LEGACY_CONSOLE_PUTCHAR => {
+            write_console(&[a0 as u8]);
+            SbiRet::success(0)
+        }
+        sbi::EID_LEGACY_CONSOLE_GETCHAR => {
+            let mut byte = [0u8];
+            // 旧版接口没有数据时返回 -1
+            let value = match read_console(&mut byte) {
+                0 => usize::MAX,
+                _ => byte[0] as usize,
+            };
+            SbiRet {
+                error: value as isize,
+                value: 0,
+            }
+        }
+        _ => SbiRet::error(sbi::SBI_ERR_NOT_SUPPORTED),
+    };
+    // 旧版接口只在 a0 返回，a1 保持不变
+    let legacy = matches!(
+        a7,
+        sbi::EID_LEGACY_SET_TIMER
+            | sbi::EID_LEGACY_CONSOLE_PUTCHAR
+            | sbi::EID_LEGACY_CONSOLE_GETCHAR
+    );
+    let a1 = if legacy { a1 } else { ret.value };
+    ctx.regs().a = [ret.error as usize, a1, a2, a3, a4, a5, a6, a7];
+    unsafe { mepc::write(mepc::read() + 4) };
+    ctx.restore()
+}
+
+fn base(fid: usize, a0: usize) -> SbiRet {
+    match fid {
+        sbi::BASE_GET_SPEC_VERSION => SbiRet::success(SPEC_VERSION),
+        sbi::BASE_GET_IMPL_ID => SbiRet::success(IMPL_ID),
+        sbi::BASE_GET_IMPL_VERSION => SbiRet::success(IMPL_VERSION),
+        sbi::BASE_PROBE_EXTENSION => SbiRet::success(probe_extension(a0) as usize),
+        sbi::BASE_GET_MVENDORID => SbiRet::success(mvendorid::read().map_or(0, |r| r.bits())),
+        sbi::BASE_GET_MARCHID => SbiRet::success(marchid::read().map_or(0, |r| r.bits())),
+        sbi::BASE_GET_MIMPID => SbiRet::success(mimpid::read().map_or(0, |r| r.bits())),
+        _ => SbiRet::error(sbi::SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn probe_extension(eid: usize) -> bool {
+    matches!(
+        eid,
+        sbi::EID_BASE
+            | sbi::EID_TIME
+            | sbi::EID_IPI
+            | sbi::EID_RFENCE
+            | sbi::EID_HSM
+            | sbi::EID_DBCN
+            | sbi::EID_LEGACY_SET_TIMER
+            | sbi::EID_LEGACY_CONSOLE_PUTCHAR
+            | sbi::EID_LEGACY_CONSOLE_GETCHAR
+    )
+}
+
+fn set_timer(lo: usize, _hi: usize) -> SbiRet {
+    #[cfg(target_pointer_width = "64")]
+    let deadline = lo as u64;
+    #[cfg(target_pointer_width = "32")]
+    let deadline = ((_hi as u64) << 32) | lo as u64;
+    crate::time_driver::set_supervisor_timer(deadline);
+    SbiRet::success(0)
+}
+
+/// 判断 hart 掩码里是否包含启动 hart；`hart_mask_base` 为 -1 表示所有 hart
+fn mask_contains_boot_hart(hart_mask: usize, hart_mask_base: usize) -> bool {
+    let hart = payload::boot_hart();
+    if hart_mask_base == usize::MAX {
+        return true;
+    }
+    hart >= hart_mask_base
+        && hart - hart_mask_base < usize::BITS as usize
+        && hart_mask & (1 << (hart - hart_mask_base)) != 0
+}
+
+fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiRet {
+    if !mask_contains_boot_hart(hart_mask, hart_mask_base) {
+        return SbiRet::error(sbi::SBI_ERR_INVALID_PARAM);
+    }
+    // 借 CLINT 的 msip 进入 M 态软件中断，再由 msoft_handler 转成 SSIP
+    unsafe {
+        IPI_PENDING = true;
+        CLINT.set_msip(payload::boot_hart());
+    }
+    SbiRet::success(0)
+}
+
+fn rfence(fid: usize) -> SbiRet {
+    match fid {
+        sbi::RFENCE_REMOTE_FENCE_I => {
+            unsafe { asm!("fence.i") };
+            SbiRet::success(0)
+        }
+        // 只有一个 hart，地址范围和 ASID 都按全部刷新处理
+        sbi::RFENCE_REMOTE_SFENCE_VMA | sbi::RFENCE_REMOTE_SFENCE_VMA_ASID => {
+            unsafe { asm!("sfence.vma") };
+            SbiRet::success(0)
+        }
+        // 不支持 H 扩展的 HFENCE
+        _ => SbiRet::error(sbi::SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn hsm(fid: usize, a0: usize) -> SbiRet {
+    const HART_STARTED: usize = 0;
+    const SUSPEND_DEFAULT_RETENTIVE: usize = 0;
+
+    // start/get_status 的 a0 是 hartid，suspend 的 a0 是挂起类型
+    let is_boot_hart = a0 == payload::boot_hart();
+    match fid {
+        sbi::HSM_HART_START if is_boot_hart => SbiRet::error(sbi::SBI_ERR_ALREADY_AVAILABLE),
+        sbi::HSM_HART_START => SbiRet::error(sbi::SBI_ERR_INVALID_PARAM),
+        // 唯一的 hart 还要跑 Embassy，不能停
+        sbi::HSM_HART_STOP => SbiRet::error(sbi::SBI_ERR_FAILED),
+        sbi::HSM_HART_GET_STATUS if is_boot_hart => SbiRet::success(HART_STARTED),
+        sbi::HSM_HART_GET_STATUS => SbiRet::error(sbi::SBI_ERR_INVALID_PARAM),
+        // 保持型挂起：等到下一个中断再返回
+        sbi::HSM_HART_SUSPEND if a0 == SUSPEND_DEFAULT_RETENTIVE => {
+            unsafe { asm!("wfi") };
+            SbiRet::success(0)
+        }
+        sbi::HSM_HART_SUSPEND => SbiRet::error(sbi::SBI_ERR_NOT_SUPPORTED),
+        _ => SbiRet::error(sbi::SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn dbcn(fid: usize, num_bytes: usize, base_lo: usize, base_hi: usize) -> SbiRet {
+    // 没有开分页，物理地址直接当指针用；高位地址必须为 0，
+    // M 态不受 PMP 约束，缓冲区碰到固件内存也要拒绝，否则 S 态能借这里读写固件
+    let firmware = pmp::firmware_range();
+    let addr_ok = base_hi == 0
+        && base_lo
+            .checked_add(num_bytes)
+            .is_some_and(|end| num_bytes == 0 || end <= firmware.start || base_lo >= firmware.end);
+    match fid {
+        sbi::DBCN_CONSOLE_WRITE | sbi::DBCN_CONSOLE_READ if !addr_ok => {
+            SbiRet::error(sbi::SBI_ERR_INVALID_ADDRESS)
+        }
+        sbi::DBCN_CONSOLE_WRITE => {
+            let buf = unsafe { core::slice::from_raw_parts(base_lo as *const u8, num_bytes) };
+            SbiRet::success(write_console(buf))
+        }
+        sbi::DBCN_CONSOLE_READ => {
+            let buf = unsafe { core::slice::from_raw_parts_mut(base_lo as *mut u8, num_bytes) };
+            SbiRet::success(read_console(buf))
+        }
+        sbi::DBCN_CONSOLE_WRITE_BYTE => {
+            write_console(&[num_bytes as u8]);
+            SbiRet::success(0)
+        }
+        _ => SbiRet::error(sbi::SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// 把整个缓冲区写到控制台，返回写出的字节数
+fn write_console(mut buf: &[u8]) -> usize {
+    let Some(console) = console::get() else {
+        return 0;
+    };
+    let len = buf.len();
+    while !buf.is_empty() {
+        let count = console.write(buf);
+        buf = &buf[count..];
+    }
+    len
+}
+
+fn read_console(buf: &mut [u8]) -> usize {
+    console::read(buf)
+}
@@ -0,0 +1,153 @@
+//! 全局堆分配器，只在开启 `alloc` feature 时编译
+//!
+//! 堆区域由链接脚本里的 `.heap` 段给出（`start_heap`..`end_heap`），
+//! 在 `_start` 里 `clear_bss` 之后初始化。分配失败时返回空指针，
+//! 由 `alloc` 的默认处理走 panic，panic handler 再打印这里的统计信息。
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::RefCell,
+    ptr::{self, NonNull},
+};
+use critical_section::{Mutex, with};
+use linked_list_allocator::Heap;
+
+/// 堆使用情况统计
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HeapStats {
+    /// 堆总大小（字节）
+    pub size: usize,
+    /// 当前已分配（字节）
+    pub used: usize,
+    /// 历史最高占用（字节）
+    pub peak: usize,
+    /// 成功分配次数
+    pub allocs: usize,
+    /// 释放次数
+    pub frees: usize,
+    /// 分配失败次数
+    pub failed: usize,
+    /// 最近一次分配失败时请求的布局
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub last_failed: Option<Layout>,
+}
+
+impl HeapStats {
+    const fn new() -> Self {
+        Self {
+            size: 0,
+            used: 0,
+            peak: 0,
+            allocs: 0,
+            frees: 0,
+            failed: 0,
+            last_failed: None,
+        }
+    }
+}
+
+struct Inner {
+    heap: Heap,
+    stats: HeapStats,
+}
+
+/// 用临界区保护的链表分配器，单核下足够用
+pub struct LockedHeap {
+    inner: Mutex<RefCell<Inner>>,
+}
+
+impl LockedHeap {
+    pub const fn empty() -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(Inner {
+                heap: Heap::empty(),
+                stats: HeapStats::new(),
+            })),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        with(|cs| {
+            let mut inner = self.inner.borrow_ref_mut(cs);
+            match inner.heap.allocate_first_fit(layout) {
+                Ok(ptr) => {
+                    let used = inner.heap.used();
+                    let stats = &mut inner.stats;
+                    stats.allocs += 1;
+                    stats.used = used;
+                    stats.peak = stats.peak.max(used);
+                    ptr.as_ptr()
+                }
+                Err(()) => {
+                    inner.stats.failed += 1;
+                    inner.stats.last_failed = Some(layout);
+                    ptr::null_mut()
+                }
+            }
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        with(|cs| {
+            let mut inner = self.inner.borrow_ref_mut(cs);
+            unsafe { inner.heap.deallocate(NonNull::new_unchecked(ptr), layout) };
+            inner.stats.frees += 1;
+            inner.stats.used = inner.heap.used();
+        })
+    }
+}
+
+#[global_allocator]
+static HEAP: LockedHeap = LockedHeap::empty();
+
+/// 用链接脚本给出的 `.heap` 段初始化全局堆，必须在 `clear_bss` 之后调用一次
+pub fn init() {
+    unsafe extern "C" {
+        fn start_heap();
+        fn end_heap();
+    }
+    let start = start_heap as usize;
+    let size = end_heap as usize - start;
+    with(|cs| {
+        let mut inner = HEAP.inner.borrow_ref_mut(cs);
+        unsafe { inner.heap.init(start as *mut u8, size) };
+        inner.stats.size = size;
+    })
+}
+
+/// 读取当前堆统计，分配器正在使用时（在分配途中 panic）返回 `None`
+pub fn stats() -> Option<HeapStats> {
+    with(|cs| {
+        HEAP.inner
+            .borrow(cs)
+            .try_borrow()
+            .ok()
+            .map(|inner| inner.stats)
+    })
+}
+
+/// 打印堆统计，panic handler 里也会调用
+pub fn report() {
+    let Some(stats) = stats() else {
+        println!("heap:    allocator busy, stats unavailable");
+        return;
+    };
+    println!(
+        "heap:    used {:#x} / {:#x}, peak {:#x}",
+        stats.used, stats.size, stats.peak
+    );
+    println!(
+        "heap:    allocs {}, frees {}, failed {}",
+        stats.allocs, stats.frees, stats.failed
+    );
+    if let Some(layout) = stats.last_failed {
+        println!(
+            "heap:    last failed alloc size={:#x} align={:#x}",
+            layout.size(),
+            layout.align()
+        );
+    }
+}
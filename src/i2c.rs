@@ -0,0 +1,666 @@
+//! DesignWare I2C 主机驱动，实现 `embedded_hal_async::i2c::I2c`
+//!
+//! 寄存器定义见 Linux i2c-designware-core。命令写进 IC_DATA_CMD 的发送 FIFO，
+//! 每个字节可以带 RESTART/STOP 标志，所以整个 transaction 一次提交，中间的重复起始
+//! 由硬件产生。SCL 被从机拉低（clock stretching）时硬件自动等待，卡死的从机由
+//! `Config::timeout` 兜底。
+//!
+//! 中断只用来唤醒：处理函数屏蔽所有中断后唤醒任务，任务里读 RAW_INTR_STAT 推进传输，
+//! 需要等待时再按当前状态打开屏蔽位。寄存器访问经过 [`Registers`]，可以换成软件模型，
+//! 下面的测试就是这样在主机上运行的（`scripts/host-test.sh`）。
+
+use core::{future::poll_fn, task::Poll};
+
+use embassy_sync::waitqueue::AtomicWaker;
+use embassy_time::{Duration, with_timeout};
+use embedded_hal::i2c::{
+    ErrorKind, ErrorType, NoAcknowledgeSource, Operation, SevenBitAddress, TenBitAddress,
+};
+
+#[cfg(not(test))]
+use crate::{
+    clock::{self, Crg, ResetTimeout, SYSCLK_I2C0_APB, SYSRST_I2C0_APB},
+    plic,
+};
+
+/// I2C0~6 的基地址
+const BASES: [usize; INSTANCES] = [
+    0x1003_0000,
+    0x1004_0000,
+    0x1005_0000,
+    0x1203_0000,
+    0x1204_0000,
+    0x1205_0000,
+    0x1206_0000,
+];
+/// I2C0~6 在 PLIC 上的中断号
+const IRQS: [usize; INSTANCES] = [35, 36, 37, 48, 49, 50, 51];
+const INSTANCES: usize = 7;
+
+// 读不到时钟频率时按 APB0 的默认值计算
+const DEFAULT_CLK_HZ: u64 = 49_500_000;
+
+const IC_CON: usize = 0x00;
+const IC_TAR: usize = 0x04;
+const IC_DATA_CMD: usize = 0x10;
+const IC_SS_SCL_HCNT: usize = 0x14;
+const IC_SS_SCL_LCNT: usize = 0x18;
+const IC_FS_SCL_HCNT: usize = 0x1c;
+const IC_FS_SCL_LCNT: usize = 0x20;
+const IC_INTR_STAT: usize = 0x2c;
+const IC_INTR_MASK: usize = 0x30;
+const IC_RAW_INTR_STAT: usize = 0x34;
+const IC_RX_TL: usize = 0x38;
+const IC_TX_TL: usize = 0x3c;
+const IC_CLR_INTR: usize = 0x40;
+const IC_CLR_TX_ABRT: usize = 0x54;
+const IC_CLR_STOP_DET: usize = 0x60;
+const IC_ENABLE: usize = 0x6c;
+const IC_TXFLR: usize = 0x74;
+const IC_RXFLR: usize = 0x78;
+const IC_TX_ABRT_SOURCE: usize = 0x80;
+const IC_ENABLE_STATUS: usize = 0x9c;
+const IC_COMP_PARAM_1: usize = 0xf4;
+
+const CON_MASTER: u32 = 1 << 0;
+const CON_SPEED_STD: u32 = 1 << 1;
+const CON_SPEED_FAST: u32 = 2 << 1;
+const CON_10BIT_MASTER: u32 = 1 << 4;
+const CON_RESTART_EN: u32 = 1 << 5;
+const CON_SLAVE_DISABLE: u32 = 1 << 6;
+
+const TAR_10BIT: u32 = 1 << 12;
+
+const CMD_READ: u32 = 1 << 8;
+const CMD_STOP: u32 = 1 << 9;
+const CMD_RESTART: u32 = 1 << 10;
+
+const INTR_RX_FULL: u32 = 1 << 2;
+const INTR_TX_EMPTY: u32 = 1 << 4;
+const INTR_TX_ABRT: u32 = 1 << 6;
+const INTR_STOP_DET: u32 = 1 << 9;
+
+const ABRT_7B_ADDR_NOACK: u32 = 1 << 0;
+const ABRT_10ADDR1_NOACK: u32 = 1 << 1;
+const ABRT_10ADDR2_NOACK: u32 = 1 << 2;
+const ABRT_TXDATA_NOACK: u32 = 1 << 3;
+const ABRT_ARB_LOST: u32 = 1 << 12;
+
+// 关闭控制器时等待 IC_ENABLE_STATUS 的最大轮询次数
+const DISABLE_POLL: usize = 10_000;
+
+static WAKERS: [AtomicWaker; INSTANCES] = [const { AtomicWaker::new() }; INSTANCES];
+
+const HANDLERS: [fn(); INSTANCES] = [
+    on_interrupt::<0>,
+    on_interrupt::<1>,
+    on_interrupt::<2>,
+    on_interrupt::<3>,
+    on_interrupt::<4>,
+    on_interrupt::<5>,
+    on_interrupt::<6>,
+];
+
+fn on_interrupt<const N: usize>() {
+    let regs = Mmio(BASES[N]);
+    if regs.read(IC_INTR_STAT) != 0 {
+        regs.write(IC_INTR_MASK, 0);
+        WAKERS[N].wake();
+    }
+}
+
+/// 控制器寄存器的读写
+pub trait Registers {
+    fn read(&self, offset: usize) -> u32;
+    fn write(&self, offset: usize, value: u32);
+}
+
+/// 直接访问 MMIO 寄存器
+pub struct Mmio(usize);
+
+impl Registers for Mmio {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.0 + offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ((self.0 + offset) as *mut u32).write_volatile(value) }
+    }
+}
+
+/// SCL 速率
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Speed {
+    /// 100 kHz
+    Standard,
+    /// 400 kHz
+    Fast,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    pub speed: Speed,
+    /// 整个 transaction 的超时
+    pub timeout: Duration,
+}
+
+impl Config {
+    pub const fn new() -> Self {
+        Self {
+            speed: Speed::Standard,
+            timeout: Duration::from_millis(100),
+        }
+    }
+
+    pub const fn speed(mut self, speed: Speed) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    pub const fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    NoAcknowledge(NoAcknowledgeSource),
+    ArbitrationLoss,
+    /// 其他原因的传输中止，值为 IC_TX_ABRT_SOURCE
+    Abort(u32),
+    Timeout,
+    /// 控制器不能发起零长度的传输
+    EmptyTransfer,
+}
+
+impl embedded_hal::i2c::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match *self {
+            Error::NoAcknowledge(source) => ErrorKind::NoAcknowledge(source),
+            Error::ArbitrationLoss => ErrorKind::ArbitrationLoss,
+            Error::Abort(_) => ErrorKind::Bus,
+            Error::Timeout | Error::EmptyTransfer => ErrorKind::Other,
+        }
+    }
+}
+
+fn abort_error(source: u32) -> Error {
+    if source & (ABRT_7B_ADDR_NOACK | ABRT_10ADDR1_NOACK | ABRT_10ADDR2_NOACK) != 0 {
+        Error::NoAcknowledge(NoAcknowledgeSource::Address)
+    } else if source & ABRT_TXDATA_NOACK != 0 {
+        Error::NoAcknowledge(NoAcknowledgeSource::Data)
+    } else if source & ABRT_ARB_LOST != 0 {
+        Error::ArbitrationLoss
+    } else {
+        Error::Abort(source)
+    }
+}
+
+// 传输进度：下一个要处理的 operation 和其中的字节
+#[derive(Clone, Copy, Default)]
+struct Cursor {
+    op: usize,
+    pos: usize,
+}
+
+fn op_info(op: &Operation<'_>) -> (bool, usize) {
+    match op {
+        Operation::Read(buf) => (true, buf.len()),
+        Operation::Write(buf) => (false, buf.len()),
+    }
+}
+
+// 读写方向和前一个非空 operation 不同时需要重复起始
+fn needs_restart(ops: &[Operation<'_>], index: usize) -> bool {
+    let is_read = op_info(&ops[index]).0;
+    ops[..index]
+        .iter()
+        .rev()
+        .map(op_info)
+        .find(|&(_, len)| len != 0)
+        .is_some_and(|(prev_read, _)| prev_read != is_read)
+}
+
+pub struct I2c<R: Registers = Mmio> {
+    regs: R,
+    index: usize,
+    config: Config,
+    tx_depth: u32,
+    rx_depth: u32,
+}
+
+#[cfg(not(test))]
+impl I2c<Mmio> {
+    /// 打开 I2C`index` 的时钟、释放复位并挂上中断，管脚需要事先用
+    /// [`crate::gpio::set_function`] 接好
+    pub fn new(index: usize, config: Config) -> Result<Self, ResetTimeout> {
+        assert!(index < INSTANCES, "invalid i2c {}", index);
+        let clk = SYSCLK_I2C0_APB + index as u32;
+        clock::bring_up(Crg::Sys, &[clk], &[SYSRST_I2C0_APB + index as u32])?;
+        let clk_hz = clock::sys_rate(clk)
+            .filter(|&hz| hz != 0)
+            .unwrap_or(DEFAULT_CLK_HZ);
+        let i2c = Self::with_registers(Mmio(BASES[index]), index, clk_hz, config);
+        plic::register(IRQS[index], HANDLERS[index]);
+        Ok(i2c)
+    }
+}
+
+impl<R: Registers> I2c<R> {
+    /// 用给定的寄存器访问方式初始化控制器，`index` 决定使用哪一个唤醒槽
+    pub fn with_registers(regs: R, index: usize, clk_hz: u64, config: Config) -> Self {
+        let param = regs.read(IC_COMP_PARAM_1);
+        let depth = |shift: u32| match (param >> shift) & 0xff {
+            0 => 8,
+            n => n + 1,
+        };
+        let mut i2c = Self {
+            tx_depth: depth(16),
+            rx_depth: depth(8),
+            regs,
+            index,
+            config,
+        };
+        i2c.init(clk_hz);
+        i2c
+    }
+
+    fn init(&mut self, clk_hz: u64) {
+        self.disable();
+        let speed = match self.config.speed {
+            Speed::Standard => CON_SPEED_STD,
+            Speed::Fast => CON_SPEED_FAST,
+        };
+        self.regs.write(
+            IC_CON,
+            CON_MASTER | speed | CON_RESTART_EN | CON_SLAVE_DISABLE,
+        );
+        // 按 I2C 规范的最小高低电平时间计算，下降沿按 300ns
+        let khz = clk_hz / 1000;
+        let hcnt = |t_high_ns: u64| ((khz * t_high_ns + 500_000) / 1_000_000).saturating_sub(8);
+        let lcnt =
+            |t_low_ns: u64| ((khz * (t_low_ns + 300) + 500_000) / 1_000_000).saturating_sub(1);
+        self.regs.write(IC_SS_SCL_HCNT, hcnt(4000) as u32);
+        self.regs.write(IC_SS_SCL_LCNT, lcnt(4700) as u32);
+        self.regs.write(IC_FS_SCL_HCNT, hcnt(600) as u32);
+        self.regs.write(IC_FS_SCL_LCNT, lcnt(1300) as u32);
+        self.regs.write(IC_TX_TL, 0);
+        self.regs.write(IC_RX_TL, 0);
+        self.regs.write(IC_INTR_MASK, 0);
+    }
+
+    fn disable(&mut self) {
+        self.regs.write(IC_ENABLE, 0);
+        for _ in 0..DISABLE_POLL {
+            if self.regs.read(IC_ENABLE_STATUS) & 1 == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    async fn transaction_inner(
+        &mut self,
+        address: u16,
+        ten_bit: bool,
+        ops: &mut [Operation<'_>],
+    ) -> Result<(), Error> {
+        let Some(last) = ops.iter().rposition(|op| op_info(op).1 != 0) else {
+            return Err(Error::EmptyTransfer);
+        };
+        let result = with_timeout(
+            self.config.timeout,
+            self.transfer(address, ten_bit, ops, last),
+        )
+        .await;
+        self.regs.write(IC_INTR_MASK, 0);
+        match result {
+            Ok(result) => result,
+            Err(_) => {
+                // 关掉控制器丢弃 FIFO，下次传输重新打开
+                self.disable();
+                Err(Error::Timeout)
+            }
+        }
+    }
+
+    async fn transfer(
+        &mut self,
+        address: u16,
+        ten_bit: bool,
+        ops: &mut [Operation<'_>],
+        last: usize,
+    ) -> Result<(), Error> {
+        // 目标地址只能在关闭时修改。没有动态 TAR 更新的核上 IC_TAR 的 10 位标志只读，
+        // 地址宽度由 IC_CON 决定，和 Linux 一样两处都设置
+        self.disable();
+        let con = self.regs.read(IC_CON) & !CON_10BIT_MASTER;
+        let tar = if ten_bit {
+            self.regs.write(IC_CON, con | CON_10BIT_MASTER);
+            (address as u32 & 0x3ff) | TAR_10BIT
+        } else {
+            self.regs.write(IC_CON, con);
+            address as u32 & 0x7f
+        };
+        self.regs.write(IC_TAR, tar);
+        self.regs.read(IC_CLR_INTR);
+        self.regs.write(IC_ENABLE, 1);
+
+        let mut tx = Cursor::default();
+        let mut rx = Cursor::default();
+        let mut outstanding = 0;
+        poll_fn(|cx| {
+            WAKERS[self.index].register(cx.waker());
+            loop {
+                let raw = self.regs.read(IC_RAW_INTR_STAT);
+                if raw & INTR_TX_ABRT != 0 {
+                    let source = self.regs.read(IC_TX_ABRT_SOURCE);
+                    self.regs.read(IC_CLR_TX_ABRT);
+                    return Poll::Ready(Err(abort_error(source)));
+                }
+                let progress = self.drain_rx(ops, &mut rx, &mut outstanding)
+                    | self.fill_tx(ops, &mut tx, &mut outstanding, last);
+                // 读命令都已发出且数据都已取回
+                let tx_done = tx.op > last;
+                if raw & INTR_STOP_DET != 0 && tx_done && outstanding == 0 {
+                    self.regs.read(IC_CLR_STOP_DET);
+                    return Poll::Ready(Ok(()));
+                }
+                if progress {
+                    continue;
+                }
+                // 剩余要读的字节不足 FIFO 深度时按实际数量设置接收阈值
+                let mut mask = INTR_TX_ABRT | INTR_STOP_DET;
+                if !tx_done {
+                    mask |= INTR_TX_EMPTY;
+                }
+                if outstanding != 0 {
+                    self.regs
+                        .write(IC_RX_TL, outstanding.min(self.rx_depth) - 1);
+                    mask |= INTR_RX_FULL;
+                }
+                self.regs.write(IC_INTR_MASK, mask);
+                return Poll::Pending;
+            }
+        })
+        .await
+    }
+
+    // 把接收 FIFO 里的数据放进读缓冲区，返回是否取到了数据
+    fn drain_rx(&self, ops: &mut [Operation<'_>], rx: &mut Cursor, outstanding: &mut u32) -> bool {
+        let mut progress = false;
+        while self.regs.read(IC_RXFLR) != 0 {
+            while rx.op < ops.len() {
+                match &ops[rx.op] {
+                    Operation::Read(buf) if rx.pos < buf.len() => break,
+                    _ => {
+                        rx.op += 1;
+                        rx.pos = 0;
+                    }
+                }
+            }
+            let Some(Operation::Read(buf)) = ops.get_mut(rx.op) else {
+                // 没有在等的读数据，丢掉
+                self.regs.read(IC_DATA_CMD);
+                continue;
+            };
+            buf[rx.pos] = self.regs.read(IC_DATA_CMD) as u8;
+            rx.pos += 1;
+            *outstanding = outstanding.saturating_sub(1);
+            progress = true;
+        }
+        progress
+    }
+
+    // 往发送 FIFO 填命令，读命令不超过接收 FIFO 的空间，返回是否写入了命令
+    fn fill_tx(
+        &self,
+        ops: &[Operation<'_>],
+        tx: &mut Cursor,
+        outstanding: &mut u32,
+        last: usize,
+    ) -> bool {
+        let mut progress = false;
+        while tx.op <= last {
+            let (is_read, len) = op_info(&ops[tx.op]);
+            if tx.pos >= len {
+                tx.op += 1;
+                tx.pos = 0;
+                continue;
+            }
+            if self.regs.read(IC_TXFLR) >= self.tx_depth {
+                break;
+            }
+            if is_read && *outstanding >= self.rx_depth {
+                break;
+            }
+            let mut cmd = match &ops[tx.op] {
+                Operation::Read(_) => CMD_READ,
+                Operation::Write(buf) => buf[tx.pos] as u32,
+            };
+            if tx.pos == 0 && needs_restart(ops, tx.op) {
+                cmd |= CMD_RESTART;
+            }
+            if tx.op == last && tx.pos == len - 1 {
+                cmd |= CMD_STOP;
+            }
+            self.regs.write(IC_DATA_CMD, cmd);
+            if is_read {
+                *outstanding += 1;
+            }
+            tx.pos += 1;
+            progress = true;
+        }
+        progress
+    }
+}
+
+impl<R: Registers> ErrorType for I2c<R> {
+    type Error = Error;
+}
+
+impl<R: Registers> embedded_hal_async::i2c::I2c<SevenBitAddress> for I2c<R> {
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.transaction_inner(address as u16, false, operations)
+            .await
+    }
+}
+
+impl<R: Registers> embedded_hal_async::i2c::I2c<TenBitAddress> for I2c<R> {
+    async fn transaction(
+        &mut self,
+        address: TenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.transaction_inner(address, true, operations).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        cell::RefCell,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+    use std::{collections::VecDeque, vec::Vec};
+
+    use embassy_time::MockDriver;
+    use embedded_hal_async::i2c::I2c as _;
+
+    use super::*;
+
+    // 寄存器级的控制器模型：命令写进 IC_DATA_CMD 时立刻在“总线”上执行
+    #[derive(Default)]
+    struct State {
+        con: u32,
+        tar: u32,
+        enable: u32,
+        mask: u32,
+        raw: u32,
+        abort_source: u32,
+        // 写进 IC_DATA_CMD 的所有命令
+        commands: Vec<u32>,
+        rx: VecDeque<u8>,
+        // 从机依次返回的数据
+        slave_data: VecDeque<u8>,
+        // 从机不应答地址
+        nack_address: bool,
+        // 从机一直拉低 SCL，命令留在发送 FIFO 里
+        stretch: bool,
+    }
+
+    #[derive(Default)]
+    struct Model(RefCell<State>);
+
+    impl Registers for &Model {
+        fn read(&self, offset: usize) -> u32 {
+            let mut state = self.0.borrow_mut();
+            match offset {
+                IC_CON => state.con,
+                IC_TAR => state.tar,
+                IC_ENABLE | IC_ENABLE_STATUS => state.enable,
+                IC_INTR_MASK => state.mask,
+                IC_INTR_STAT => state.raw & state.mask,
+                IC_RAW_INTR_STAT => state.raw,
+                IC_TX_ABRT_SOURCE => state.abort_source,
+                IC_CLR_INTR => {
+                    state.raw = 0;
+                    0
+                }
+                IC_CLR_TX_ABRT => {
+                    state.raw &= !INTR_TX_ABRT;
+                    0
+                }
+                IC_CLR_STOP_DET => {
+                    state.raw &= !INTR_STOP_DET;
+                    0
+                }
+                IC_TXFLR if state.stretch => state.commands.len() as u32,
+                IC_RXFLR => state.rx.len() as u32,
+                IC_DATA_CMD => state.rx.pop_front().unwrap_or(0) as u32,
+                // FIFO 深度按默认的 8
+                _ => 0,
+            }
+        }
+
+        fn write(&self, offset: usize, value: u32) {
+            let mut state = self.0.borrow_mut();
+            match offset {
+                IC_CON => state.con = value,
+                IC_TAR => state.tar = value,
+                IC_ENABLE => state.enable = value & 1,
+                IC_INTR_MASK => state.mask = value,
+                IC_DATA_CMD => {
+                    assert_eq!(state.enable, 1, "command written while disabled");
+                    state.commands.push(value);
+                    if state.stretch {
+                        return;
+                    }
+                    if state.nack_address {
+                        state.abort_source = ABRT_7B_ADDR_NOACK;
+                        state.raw |= INTR_TX_ABRT | INTR_STOP_DET;
+                        return;
+                    }
+                    if value & CMD_READ != 0 {
+                        let byte = state.slave_data.pop_front().expect("unexpected read");
+                        state.rx.push_back(byte);
+                    }
+                    if value & CMD_STOP != 0 {
+                        state.raw |= INTR_STOP_DET;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn i2c(model: &Model) -> I2c<&Model> {
+        I2c::with_registers(model, 0, DEFAULT_CLK_HZ, Config::new())
+    }
+
+    // 没有中断，反复 poll 直到完成
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        for _ in 0..1000 {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+        panic!("transfer did not finish");
+    }
+
+    #[test]
+    fn write_7bit() {
+        let model = Model::default();
+        let mut i2c = i2c(&model);
+        assert_eq!(block_on(i2c.write(0x50u8, &[1, 2, 3])), Ok(()));
+        let state = model.0.borrow();
+        assert_eq!(state.tar, 0x50);
+        assert_eq!(state.con & CON_10BIT_MASTER, 0);
+        assert_eq!(state.commands, [1, 2, 3 | CMD_STOP]);
+    }
+
+    #[test]
+    fn write_10bit() {
+        let model = Model::default();
+        let mut i2c = i2c(&model);
+        assert_eq!(block_on(i2c.write(0x2a5u16, &[7])), Ok(()));
+        let state = model.0.borrow();
+        assert_eq!(state.tar, 0x2a5 | TAR_10BIT);
+        assert_ne!(state.con & CON_10BIT_MASTER, 0);
+    }
+
+    #[test]
+    fn write_read_repeated_start() {
+        let model = Model::default();
+        model.0.borrow_mut().slave_data = VecDeque::from([0xaa, 0xbb]);
+        let mut i2c = i2c(&model);
+        let mut buf = [0; 2];
+        assert_eq!(block_on(i2c.write_read(0x50u8, &[0x10], &mut buf)), Ok(()));
+        assert_eq!(buf, [0xaa, 0xbb]);
+        assert_eq!(
+            model.0.borrow().commands,
+            [0x10, CMD_READ | CMD_RESTART, CMD_READ | CMD_STOP]
+        );
+    }
+
+    #[test]
+    fn address_nack() {
+        let model = Model::default();
+        model.0.borrow_mut().nack_address = true;
+        let mut i2c = i2c(&model);
+        assert_eq!(
+            block_on(i2c.write(0x50u8, &[1])),
+            Err(Error::NoAcknowledge(NoAcknowledgeSource::Address))
+        );
+        // 中止状态已经清掉，下一次传输不受影响
+        assert_eq!(model.0.borrow().raw & INTR_TX_ABRT, 0);
+    }
+
+    #[test]
+    fn timeout() {
+        let model = Model::default();
+        model.0.borrow_mut().stretch = true;
+        let mut i2c = i2c(&model);
+        let mut future = pin!(i2c.write(0x50u8, &[1]));
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        MockDriver::get().advance(Config::new().timeout);
+        assert_eq!(
+            future.as_mut().poll(&mut cx),
+            Poll::Ready(Err(Error::Timeout))
+        );
+        // 超时后关掉控制器丢弃 FIFO
+        assert_eq!(model.0.borrow().enable, 0);
+    }
+}
@@ -1,76 +1,287 @@
 // use log::max_level;
 
-use crate::{
-    console::PLATFORM,
-    // gpio::{GPIO_BASE, init_gpio_as_output, set_gpio_output},
+// use crate::gpio::{GPIO_BASE, init_gpio_as_output, set_gpio_output};
+#[cfg(feature = "log")]
+use core::{
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
 };
-use core::str::FromStr;
-// use log::{Level, LevelFilter};
+#[cfg(all(feature = "log", not(test)))]
+use log::Level;
+#[cfg(feature = "log")]
+use log::LevelFilter;
+
+// 打印宏只写进控制台的发送缓冲区，由 console::drain_task 发到 UART；
+// 中断和 panic 里都可以使用，一行输出不会被别处的打印拆开
+
+#[macro_export]
+#[allow(unused)]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::console::_print(core::format_args!($($arg)*), false)
+    };
+}
 
 #[macro_export]
 #[allow(unused)]
 macro_rules! println {
     () => {
-        $crate::print!("\n\r")
+        $crate::console::_print(core::format_args!(""), true)
+    };
+    ($($arg:tt)*) => {
+        $crate::console::_print(core::format_args!($($arg)*), true)
     };
-    ($($arg:tt)*) => {{
-        use core::fmt::Write;
-            #[allow(static_mut_refs)]
-            let console = unsafe { PLATFORM.console.as_mut().unwrap()};
-            console.write_fmt(core::format_args!($($arg)*)).unwrap();
-            console.write_str("\n\r").unwrap();
-    }};
 }
 
-// /// Simple logger implementation for RustSBI that supports colored output.
-// pub struct Logger;
-
-// impl Logger {
-//     /// Initialize the logger with log level from RUST_LOG env var or default to Info.
-//     pub fn init() -> Result<(), log::SetLoggerError> {
-//         // Set max log level from LOG_LEVEL from config file, otherwise use Info
-//         let max_level = LevelFilter::from_str("INFO").unwrap_or(LevelFilter::Info);
-
-//         log::set_max_level(max_level);
-
-//         println!("这里还有啥问题么？");
-//         unsafe { log::set_logger_racy(&Logger) }
-//     }
-// }
-
-// impl log::Log for Logger {
-//     // Always enable logging for all log levels
-//     #[inline]
-//     fn enabled(&self, _metadata: &log::Metadata) -> bool {
-//         true
-//     }
-
-//     // Log messages with color-coded levels
-//     #[inline]
-//     fn log(&self, record: &log::Record) {
-//         // ANSI color codes for different log levels
-//         const ERROR_COLOR: u8 = 31; // Red
-//         const WARN_COLOR: u8 = 93; // Bright yellow
-//         const INFO_COLOR: u8 = 32; // Green
-//         const DEBUG_COLOR: u8 = 36; // Cyan
-//         const TRACE_COLOR: u8 = 90; // Bright black
-
-//         let color_code = match record.level() {
-//             Level::Error => ERROR_COLOR,
-//             Level::Warn => WARN_COLOR,
-//             Level::Info => INFO_COLOR,
-//             Level::Debug => DEBUG_COLOR,
-//             Level::Trace => TRACE_COLOR,
-//         };
-
-//         println!(
-//             "\x1b[1;37m[RustSBI] \x1b[1;{color_code}m{:^5}\x1b[0m - {}",
-//             record.level(),
-//             record.args(),
-//         );
-//     }
-
-//     // No-op flush since we use println! which is already line-buffered
-//     #[inline]
-//     fn flush(&self) {}
-// }
+/// 同步输出，返回时已经写到 UART
+#[macro_export]
+#[allow(unused)]
+macro_rules! eprint {
+    ($($arg:tt)*) => {
+        $crate::console::_eprint(core::format_args!($($arg)*), false)
+    };
+}
+
+#[macro_export]
+#[allow(unused)]
+macro_rules! eprintln {
+    () => {
+        $crate::console::_eprint(core::format_args!(""), true)
+    };
+    ($($arg:tt)*) => {
+        $crate::console::_eprint(core::format_args!($($arg)*), true)
+    };
+}
+
+/// Simple logger implementation that supports colored output.
+///
+/// 每条记录带上 `embassy_time::Instant` 的启动时间、hart 编号和模块路径。
+/// 默认等级和按模块的过滤规则在编译时由 `RUST_LOG` 环境变量给出，语法同 env_logger，
+/// 例如 `RUST_LOG=info,embassy_app::i2c=trace,embassy_executor=off`；
+/// 默认等级可以在运行时用 [`set_level`] 修改。
+#[cfg(feature = "log")]
+pub struct Logger;
+
+#[cfg(all(feature = "log", not(test)))]
+static LOGGER: Logger = Logger;
+
+/// 最多支持的按模块过滤规则数
+#[cfg(feature = "log")]
+const MAX_FILTERS: usize = 16;
+
+#[cfg(feature = "log")]
+static mut FILTERS: [(&str, LevelFilter); MAX_FILTERS] = [("", LevelFilter::Off); MAX_FILTERS];
+#[cfg(feature = "log")]
+static mut FILTER_COUNT: usize = 0;
+#[cfg(feature = "log")]
+static DEFAULT_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+#[cfg(feature = "log")]
+static HART_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "log")]
+fn level_filter(value: usize) -> LevelFilter {
+    LevelFilter::iter()
+        .find(|&level| level as usize == value)
+        .unwrap_or(LevelFilter::Trace)
+}
+
+#[cfg(feature = "log")]
+impl Logger {
+    /// Initialize the logger with log level from RUST_LOG env var or default to Info.
+    #[cfg(not(test))]
+    pub fn init(hartid: usize) -> Result<(), log::SetLoggerError> {
+        HART_ID.store(hartid, Ordering::Relaxed);
+        // 解析编译时给出的 RUST_LOG
+        let spec = option_env!("RUST_LOG").unwrap_or("info");
+        let (count, default) = parse_spec(spec, unsafe { &mut FILTERS });
+        unsafe { FILTER_COUNT = count };
+        if let Some(level) = default {
+            DEFAULT_LEVEL.store(level as usize, Ordering::Relaxed);
+        }
+        update_max_level();
+
+        unsafe { log::set_logger_racy(&LOGGER) }
+    }
+
+    fn filter(target: &str) -> LevelFilter {
+        let filters = unsafe { &FILTERS[..FILTER_COUNT] };
+        find_level(
+            filters,
+            target,
+            level_filter(DEFAULT_LEVEL.load(Ordering::Relaxed)),
+        )
+    }
+}
+
+// 解析逗号分隔的 `level` 和 `module=level`，按模块的规则依次放进 `filters`，放不下的丢掉；
+// 返回规则条数和默认等级，等级写错的项直接忽略
+#[cfg(feature = "log")]
+fn parse_spec<'a>(
+    spec: &'a str,
+    filters: &mut [(&'a str, LevelFilter)],
+) -> (usize, Option<LevelFilter>) {
+    let mut count = 0;
+    let mut default = None;
+    for directive in spec.split(',') {
+        let directive = directive.trim();
+        match directive.split_once('=') {
+            Some((module, level)) => {
+                let Ok(level) = LevelFilter::from_str(level.trim()) else {
+                    continue;
+                };
+                if let Some(filter) = filters.get_mut(count) {
+                    *filter = (module.trim(), level);
+                    count += 1;
+                }
+            }
+            None => {
+                if let Ok(level) = LevelFilter::from_str(directive) {
+                    default = Some(level);
+                }
+            }
+        }
+    }
+    (count, default)
+}
+
+// 最长匹配的模块规则优先，没有匹配时用默认等级
+#[cfg(feature = "log")]
+fn find_level(filters: &[(&str, LevelFilter)], target: &str, default: LevelFilter) -> LevelFilter {
+    filters
+        .iter()
+        .filter(|(module, _)| {
+            target
+                .strip_prefix(module)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        })
+        .max_by_key(|(module, _)| module.len())
+        .map(|&(_, level)| level)
+        .unwrap_or(default)
+}
+
+// log 的全局最大等级取默认等级和所有模块规则里最高的一个，其余交给 enabled 判断
+#[cfg(feature = "log")]
+fn update_max_level() {
+    let filters = unsafe { &FILTERS[..FILTER_COUNT] };
+    let max = filters.iter().map(|&(_, level)| level).fold(
+        level_filter(DEFAULT_LEVEL.load(Ordering::Relaxed)),
+        Ord::max,
+    );
+    // 目标没有 CAS，log 只提供 racy 版本；单 hart 上和 set_logger_racy 一样安全
+    unsafe { log::set_max_level_racy(max) };
+}
+
+/// 运行时修改默认等级，按模块的规则不受影响
+#[cfg(feature = "log")]
+pub fn set_level(level: LevelFilter) {
+    DEFAULT_LEVEL.store(level as usize, Ordering::Relaxed);
+    update_max_level();
+}
+
+#[cfg(all(feature = "log", not(test)))]
+impl log::Log for Logger {
+    #[inline]
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= Self::filter(metadata.target())
+    }
+
+    // Log messages with color-coded levels
+    #[inline]
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // ANSI color codes for different log levels
+        const ERROR_COLOR: u8 = 31; // Red
+        const WARN_COLOR: u8 = 93; // Bright yellow
+        const INFO_COLOR: u8 = 32; // Green
+        const DEBUG_COLOR: u8 = 36; // Cyan
+        const TRACE_COLOR: u8 = 90; // Bright black
+
+        let color_code = match record.level() {
+            Level::Error => ERROR_COLOR,
+            Level::Warn => WARN_COLOR,
+            Level::Info => INFO_COLOR,
+            Level::Debug => DEBUG_COLOR,
+            Level::Trace => TRACE_COLOR,
+        };
+
+        let micros = embassy_time::Instant::now().as_micros();
+        println!(
+            "\x1b[90m[{:>5}.{:06}] hart{}\x1b[0m \x1b[1;{color_code}m{:<5}\x1b[0m \x1b[1;37m{}\x1b[0m - {}",
+            micros / 1_000_000,
+            micros % 1_000_000,
+            HART_ID.load(Ordering::Relaxed),
+            record.level(),
+            record.module_path().unwrap_or(record.target()),
+            record.args(),
+        );
+    }
+
+    // 同步发完控制台缓冲区里的日志
+    #[inline]
+    fn flush(&self) {
+        crate::console::flush();
+    }
+}
+
+#[cfg(all(feature = "log", test))]
+mod tests {
+    use super::*;
+
+    fn parse(spec: &str) -> (std::vec::Vec<(&str, LevelFilter)>, Option<LevelFilter>) {
+        let mut filters = [("", LevelFilter::Off); 4];
+        let (count, default) = parse_spec(spec, &mut filters);
+        (filters[..count].to_vec(), default)
+    }
+
+    #[test]
+    fn parse_default_and_modules() {
+        let (filters, default) = parse("info, embassy_app::i2c = trace ,embassy_executor=off");
+        assert_eq!(default, Some(LevelFilter::Info));
+        assert_eq!(
+            filters,
+            [
+                ("embassy_app::i2c", LevelFilter::Trace),
+                ("embassy_executor", LevelFilter::Off)
+            ]
+        );
+        // 等级不区分大小写，后面的默认等级覆盖前面的
+        assert_eq!(parse("WARN,debug").1, Some(LevelFilter::Debug));
+    }
+
+    #[test]
+    fn parse_ignores_invalid() {
+        let (filters, default) = parse(",verbose,embassy_app=loud,,embassy_app::spi=warn,");
+        assert_eq!(default, None);
+        assert_eq!(filters, [("embassy_app::spi", LevelFilter::Warn)]);
+        assert_eq!(parse(""), (std::vec::Vec::new(), None));
+    }
+
+    #[test]
+    fn parse_drops_extra_filters() {
+        let (filters, default) = parse("a=error,b=warn,c=info,d=debug,e=trace,error");
+        assert_eq!(filters.len(), 4);
+        assert_eq!(filters[3], ("d", LevelFilter::Debug));
+        assert_eq!(default, Some(LevelFilter::Error));
+    }
+
+    #[test]
+    fn longest_module_wins() {
+        let filters = [
+            ("embassy_app", LevelFilter::Warn),
+            ("embassy_app::i2c", LevelFilter::Trace),
+            ("embassy_executor", LevelFilter::Off),
+        ];
+        let level = |target| find_level(&filters, target, LevelFilter::Info);
+        assert_eq!(level("embassy_app"), LevelFilter::Warn);
+        assert_eq!(level("embassy_app::spi"), LevelFilter::Warn);
+        assert_eq!(level("embassy_app::i2c"), LevelFilter::Trace);
+        assert_eq!(level("embassy_app::i2c::bus"), LevelFilter::Trace);
+        assert_eq!(level("embassy_executor::raw"), LevelFilter::Off);
+        // 只按 `::` 分隔的路径匹配，前缀相同的别的模块用默认等级
+        assert_eq!(level("embassy_app_extra"), LevelFilter::Info);
+        assert_eq!(level("embassy_app::i2c2"), LevelFilter::Warn);
+        assert_eq!(level("riscv"), LevelFilter::Info);
+    }
+}
@@ -0,0 +1,115 @@
+//! 长时间 poll 检测
+//!
+//! 执行器是单线程的，某个任务在一次 poll 里忙等或者阻塞（例如等 UART 发完），
+//! 其它任务都跑不了。开启 `long-poll` feature 后，[`crate::task_stats`] 的
+//! poll 开始钩子把 `开始时间 + 阈值` 交给 time_driver，比已经装好的定时器
+//! 更早时才写比较寄存器，否则由原来的定时器中断检查；poll 超过阈值时定时器中断打断正在运行的任务，
+//! 在中断里打印任务名、已经运行的时间和被打断处的 `mepc`（S 态为 `sepc`），
+//! 用 `addr2line` 就能找到卡住的代码。每次 poll 最多报告一次。
+
+use embassy_time::Duration;
+use portable_atomic::{AtomicU64, Ordering};
+
+use crate::{
+    monitor,
+    shell::{self, Command},
+    task_stats, time_driver,
+};
+
+/// 默认阈值（微秒）
+const DEFAULT_THRESHOLD_US: u64 = 10_000;
+/// 命令行能设置的最大阈值（毫秒），再长也就等于关掉了
+const MAX_THRESHOLD_MS: u64 = 60_000;
+
+// 阈值（微秒），0 表示关闭
+static THRESHOLD_US: AtomicU64 = AtomicU64::new(DEFAULT_THRESHOLD_US);
+static REPORTED: AtomicU64 = AtomicU64::new(0);
+
+/// 设置阈值，`None` 关闭检测
+pub fn set_threshold(threshold: Option<Duration>) {
+    let us = threshold.map_or(0, |threshold| threshold.as_micros().max(1));
+    THRESHOLD_US.store(us, Ordering::Relaxed);
+}
+
+/// 当前阈值，关闭时为 `None`
+pub fn threshold() -> Option<Duration> {
+    match THRESHOLD_US.load(Ordering::Relaxed) {
+        0 => None,
+        us => Some(Duration::from_micros(us)),
+    }
+}
+
+/// 注册命令行的 `longpoll` 命令
+pub fn init() {
+    shell::register(Command {
+        name: "longpoll",
+        help: "longpoll [<ms>|off]        show or set the long-poll threshold",
+        run: cmd_longpoll,
+    });
+}
+
+fn cmd_longpoll(args: &[&str]) -> Result<(), &'static str> {
+    match args.get(1).copied() {
+        None => {}
+        Some("off") => set_threshold(None),
+        Some(ms) => {
+            let ms = monitor::parse_number(ms)
+                .filter(|&ms| ms <= MAX_THRESHOLD_MS)
+                .ok_or("threshold must be 0..=60000 ms")?;
+            set_threshold(Some(Duration::from_millis(ms)));
+        }
+    }
+    match threshold() {
+        Some(threshold) => println!("threshold {} us", threshold.as_micros()),
+        None => println!("threshold off"),
+    }
+    println!("reported {} long polls", reported());
+    Ok(())
+}
+
+/// 启动以来报告过的长时间 poll 次数
+pub fn reported() -> u64 {
+    REPORTED.load(Ordering::Relaxed)
+}
+
+/// poll 开始，`now` 为 mtime
+pub fn poll_begin(now: u64) {
+    if let Some(threshold) = threshold() {
+        // 阈值可以从别处设置，乘法要防溢出
+        let ticks = threshold
+            .as_micros()
+            .saturating_mul(time_driver::mtime_hz())
+            / 1_000_000;
+        time_driver::set_poll_deadline(now.saturating_add(ticks));
+    }
+}
+
+/// poll 结束
+pub fn poll_end() {
+    time_driver::set_poll_deadline(u64::MAX);
+}
+
+// 在定时器中断里读被打断处的地址
+fn interrupted_pc() -> usize {
+    #[cfg(feature = "machine")]
+    return riscv::register::mepc::read();
+    #[cfg(feature = "supervisor")]
+    return riscv::register::sepc::read();
+}
+
+/// poll 超过阈值，由 time_driver 在定时器中断里调用
+pub fn report() {
+    REPORTED.fetch_add(1, Ordering::Relaxed);
+    let pc = interrupted_pc();
+    let hz = time_driver::mtime_hz().max(1);
+    match task_stats::current() {
+        Some((task, ticks)) => println!(
+            "long-poll: task {} ({:#x}) running for {} us, pc {:#x}",
+            task.name,
+            task.id,
+            ticks * 1_000_000 / hz,
+            pc
+        ),
+        None => println!("long-poll: unknown task, pc {:#x}", pc),
+    }
+}
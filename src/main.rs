@@ -2,27 +2,84 @@
 #![no_main]
 #![allow(static_mut_refs)]
 #![allow(explicit_builtin_cfgs_in_flags)]
-pub mod console;
-mod gpio;
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+// 宏按声明顺序可见，其它模块都要用 println!，log 放在最前面
 #[macro_use]
 mod log;
+mod boot;
+mod clock;
+pub mod console;
+#[cfg(feature = "defmt")]
+mod defmt_uart;
+mod gpio;
+mod gpio_irq;
+#[cfg(feature = "payload")]
+mod handler;
+#[cfg(feature = "alloc")]
+mod heap;
+#[cfg(feature = "i2c")]
+mod i2c;
+#[cfg(feature = "long-poll")]
+mod long_poll;
+mod monitor;
+#[cfg(feature = "payload")]
+mod payload;
+mod plic;
+mod pmp;
+#[cfg(feature = "pwm")]
+mod pwm;
+mod ramlog;
+#[cfg(any(feature = "supervisor", feature = "payload"))]
+mod sbi;
+mod shell;
+#[cfg(feature = "spi")]
+mod spi;
+mod stack_guard;
+#[cfg(feature = "supervisor")]
+mod supervisor;
+mod task_stats;
 mod time_driver;
-
-use core::{arch::asm, mem::forget, ptr::NonNull};
+mod tx_ring;
+mod watchdog;
+
+#[cfg(all(feature = "machine", feature = "supervisor"))]
+compile_error!("features `machine` and `supervisor` are mutually exclusive");
+#[cfg(not(any(feature = "machine", feature = "supervisor")))]
+compile_error!("one of the features `machine` or `supervisor` must be enabled");
+#[cfg(all(feature = "payload", feature = "thread-executor"))]
+compile_error!("feature `payload` drives the executor from interrupts, disable `thread-executor`");
+#[cfg(all(feature = "payload", feature = "pmp-lock"))]
+compile_error!("locked PMP entries also apply to S-mode, disable `pmp-lock` with `payload`");
+#[cfg(all(feature = "qemu", feature = "watchdog"))]
+compile_error!("QEMU virt has no JH7110 watchdog, disable `watchdog` with `qemu`");
+
+use core::{arch::global_asm, mem::forget, ops::Range, ptr::NonNull};
 
 // use ::log::{error, info};
+#[cfg(target_pointer_width = "64")]
 use aclint::SifiveClint;
-use console::{PLATFORM, Uart16550Wrap};
+#[cfg(feature = "thread-executor")]
 use embassy_executor::Executor;
+use embassy_executor::Spawner;
 use embassy_time::{Duration, Timer};
+#[cfg(not(feature = "qemu"))]
+use embedded_hal::digital::StatefulOutputPin;
 use fast_trap::{FastContext, FastResult, FlowContext, FreeTrapStack};
-use gpio::{GPIO_BASE, init_gpio_as_output, set_gpio_output, toggle_gpio};
+#[cfg(not(feature = "qemu"))]
+use gpio::Gpio;
 // use log::Logger;
+#[cfg(feature = "supervisor")]
+use riscv::register::{sstatus, stvec};
+#[cfg(feature = "machine")]
 use riscv::{
     interrupt::{Exception, Interrupt, Trap},
-    register::{mcause, mepc, mtval, mtvec},
+    register::{mcause, mepc, mstatus, mtval, mtvec},
 };
 use static_cell::StaticCell;
+#[cfg(feature = "supervisor")]
+use supervisor::fast_handler;
 
 // #[macro_use]
 // extern crate log;
@@ -102,75 +159,170 @@ async fn run_simple() {
     }
 }
 
+#[cfg(not(feature = "qemu"))]
 #[embassy_executor::task]
 async fn run_gpio() {
     // 初始化 GPIO5 作为输出
     println!("Hello, world!run_gpio");
-    init_gpio_as_output(GPIO_BASE, 55);
+    let mut gpio = Gpio::take().unwrap();
+    let mut led = gpio.pin::<55>().unwrap().into_output();
+    // 这个任务停下来时喂狗任务就不再喂狗
+    #[cfg(feature = "watchdog")]
+    let check_in = watchdog::register().unwrap();
 
     loop {
         // 切换 LED 状态验证 Embassy 运行
-        unsafe {
-            toggle_gpio(GPIO_BASE, 55);
-        }
+        led.toggle().unwrap();
+        #[cfg(feature = "watchdog")]
+        check_in.check_in();
 
         // 1s延迟
         Timer::after(Duration::from_millis(1_000)).await;
     }
 }
 
+// aclint 的汇编只有 RV64 版本，RV32 上直接按偏移访问 CLINT，
+// 64 位的 mtime/mtimecmp 拆成两次 32 位访问
+#[cfg(target_pointer_width = "32")]
+const CLINT_MSIP: usize = 0x0;
+#[cfg(target_pointer_width = "32")]
+const CLINT_MTIMECMP: usize = 0x4000;
+#[cfg(target_pointer_width = "32")]
+const CLINT_MTIME: usize = 0xbff8;
+
 pub struct SifiveClintWrap {
-    inner: *const SifiveClint,
+    base: usize,
 }
 
 impl SifiveClintWrap {
     pub const fn new(base: usize) -> Self {
-        Self {
-            inner: base as *const SifiveClint,
-        }
+        Self { base }
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[inline(always)]
+    fn inner(&self) -> &SifiveClint {
+        unsafe { &*(self.base as *const SifiveClint) }
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    #[inline(always)]
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
     }
 }
 
 impl SifiveClintWrap {
+    #[cfg(target_pointer_width = "64")]
     #[inline(always)]
     fn read_mtime(&self) -> u64 {
-        // println!("Hello, world!16");
-        let result = unsafe { (*self.inner).read_mtime() };
-        // println!("Hello, world!17");
-        result
+        self.inner().read_mtime()
+    }
+
+    // 低位进位时高位会变，前后两次读到的高位相同才说明低位没有回绕
+    #[cfg(target_pointer_width = "32")]
+    #[inline(always)]
+    fn read_mtime(&self) -> u64 {
+        let (lo, hi) = (self.reg(CLINT_MTIME), self.reg(CLINT_MTIME + 4));
+        loop {
+            let high = unsafe { hi.read_volatile() };
+            let low = unsafe { lo.read_volatile() };
+            if unsafe { hi.read_volatile() } == high {
+                return ((high as u64) << 32) | low as u64;
+            }
+        }
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[inline(always)]
+    fn write_mtime(&self, val: u64) {
+        self.inner().write_mtime(val)
     }
 
+    // 先把低位清零，避免写高位时低位正好进位
+    #[cfg(target_pointer_width = "32")]
     #[inline(always)]
     fn write_mtime(&self, val: u64) {
-        unsafe { (*self.inner).write_mtime(val) }
+        let (lo, hi) = (self.reg(CLINT_MTIME), self.reg(CLINT_MTIME + 4));
+        unsafe {
+            lo.write_volatile(0);
+            hi.write_volatile((val >> 32) as u32);
+            lo.write_volatile(val as u32);
+        }
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[inline(always)]
+    fn read_mtimecmp(&self, hart_idx: usize) -> u64 {
+        self.inner().read_mtimecmp(hart_idx)
     }
 
+    // mtimecmp 只由本 hart 写，不会在两次读之间变化
+    #[cfg(target_pointer_width = "32")]
     #[inline(always)]
     fn read_mtimecmp(&self, hart_idx: usize) -> u64 {
-        unsafe { (*self.inner).read_mtimecmp(hart_idx) }
+        let offset = CLINT_MTIMECMP + hart_idx * 8;
+        let (lo, hi) = (self.reg(offset), self.reg(offset + 4));
+        unsafe { ((hi.read_volatile() as u64) << 32) | lo.read_volatile() as u64 }
     }
 
+    #[cfg(target_pointer_width = "64")]
     #[inline(always)]
     fn write_mtimecmp(&self, hart_idx: usize, val: u64) {
-        unsafe { (*self.inner).write_mtimecmp(hart_idx, val) }
+        self.inner().write_mtimecmp(hart_idx, val)
     }
 
+    // 先把低位写成最大值，中途的新旧组合不会比最终值更早，避免误触发
+    #[cfg(target_pointer_width = "32")]
+    #[inline(always)]
+    fn write_mtimecmp(&self, hart_idx: usize, val: u64) {
+        let offset = CLINT_MTIMECMP + hart_idx * 8;
+        let (lo, hi) = (self.reg(offset), self.reg(offset + 4));
+        unsafe {
+            lo.write_volatile(u32::MAX);
+            hi.write_volatile((val >> 32) as u32);
+            lo.write_volatile(val as u32);
+        }
+    }
+
+    #[cfg(target_pointer_width = "64")]
     #[inline(always)]
     fn read_msip(&self, hart_idx: usize) -> bool {
-        unsafe { (*self.inner).read_msip(hart_idx) }
+        self.inner().read_msip(hart_idx)
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    #[inline(always)]
+    fn read_msip(&self, hart_idx: usize) -> bool {
+        unsafe { self.reg(CLINT_MSIP + hart_idx * 4).read_volatile() & 1 != 0 }
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[inline(always)]
+    fn set_msip(&self, hart_idx: usize) {
+        self.inner().set_msip(hart_idx)
     }
 
+    #[cfg(target_pointer_width = "32")]
     #[inline(always)]
     fn set_msip(&self, hart_idx: usize) {
-        unsafe { (*self.inner).set_msip(hart_idx) }
+        unsafe { self.reg(CLINT_MSIP + hart_idx * 4).write_volatile(1) }
     }
 
+    #[cfg(target_pointer_width = "64")]
     #[inline(always)]
     fn clear_msip(&self, hart_idx: usize) {
-        unsafe { (*self.inner).clear_msip(hart_idx) }
+        self.inner().clear_msip(hart_idx)
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    #[inline(always)]
+    fn clear_msip(&self, hart_idx: usize) {
+        unsafe { self.reg(CLINT_MSIP + hart_idx * 4).write_volatile(0) }
     }
 }
 
+#[cfg(feature = "thread-executor")]
 static EXECUTOR: StaticCell<Executor> = StaticCell::new();
 // static mut EXECUTOR: Option<Executor> = None;
 
@@ -179,6 +331,7 @@ unsafe impl Send for SifiveClintWrap {}
 
 static mut CLINT: SifiveClintWrap = SifiveClintWrap::new(0x2000000);
 
+#[cfg(feature = "machine")]
 pub extern "C" fn fast_handler(
     mut ctx: FastContext,
     a1: usize,
@@ -198,16 +351,19 @@ pub extern "C" fn fast_handler(
     };
     let cause = mcause::read();
     // TODO: 从来没看到打印，根本没进入过trap=_=
+    // payload 的每次 SBI 调用都会进来，不打印
+    #[cfg(not(feature = "payload"))]
     println!("TRAP: cause{:?}, code={:#x}", cause.cause(), cause.code());
 
     match cause.cause().try_into() {
         Ok(cause) => {
             match cause {
                 // Handle Msoft
-                // Trap::Interrupt(Interrupt::MachineSoft) => {
-                //     save_regs(&mut ctx);
-                //     handler::msoft_handler(ctx)
-                // }
+                #[cfg(feature = "payload")]
+                Trap::Interrupt(Interrupt::MachineSoft) => {
+                    save_regs(&mut ctx);
+                    handler::msoft_handler(ctx)
+                }
                 // Handle MTimer
                 Trap::Interrupt(Interrupt::MachineTimer) => {
                     // 会导致中断委托给S态，因而在embassy这里应该不做处理？
@@ -216,10 +372,27 @@ pub extern "C" fn fast_handler(
                     save_regs(&mut ctx);
                     ctx.restore()
                 }
+                // Handle MExternal
+                Trap::Interrupt(Interrupt::MachineExternal) => {
+                    crate::plic::handle_interrupt();
+
+                    save_regs(&mut ctx);
+                    ctx.restore()
+                }
+                // Decode PMP / bus access faults before stopping
+                Trap::Exception(
+                    exception @ (Exception::InstructionFault
+                    | Exception::LoadFault
+                    | Exception::StoreFault),
+                ) => {
+                    crate::pmp::report_fault(exception, mtval::read());
+                    unsupported_trap(Some(Trap::Exception(exception)))
+                }
                 // Handle SBI calls
-                // Trap::Exception(Exception::SupervisorEnvCall) => {
-                //     handler::sbi_call_handler(ctx, a1, a2, a3, a4, a5, a6, a7)
-                // }
+                #[cfg(feature = "payload")]
+                Trap::Exception(Exception::SupervisorEnvCall) => {
+                    handler::sbi_call_handler(ctx, a1, a2, a3, a4, a5, a6, a7)
+                }
                 // Handle illegal instructions
                 // Trap::Exception(Exception::IllegalInstruction) => {
                 //     if mstatus::read().mpp() == mstatus::MPP::Machine {
@@ -246,6 +419,7 @@ pub extern "C" fn fast_handler(
         }
     }
 }
+#[cfg(feature = "machine")]
 pub fn unsupported_trap(trap: Option<Trap<Interrupt, Exception>>) -> ! {
     println!("-----------------------------");
     println!("trap:    {trap:?}");
@@ -259,11 +433,19 @@ const STACK_SIZE: usize = 16 * 1024;
 
 #[unsafe(link_section = ".bss.stack")]
 static mut HART0_STACK: Stack = Stack([0; STACK_SIZE]);
+// 陷入时 fast-trap 通过这个指针保存上下文，一直有效，不能是临时变量
+static mut TRAP_CONTEXT: FlowContext = FlowContext::ZERO;
 
 #[repr(C, align(128))]
 pub(crate) struct Stack([u8; STACK_SIZE]);
 
 impl Stack {
+    /// Returns the address range covered by this stack.
+    fn range(&self) -> Range<usize> {
+        let range = self.0.as_ptr_range();
+        range.start as usize..range.end as usize
+    }
+
     // #[inline]
     // pub fn hart_context(&mut self) -> &mut HartContext {
     //     unsafe { &mut *self.0.as_mut_ptr().cast() }
@@ -285,7 +467,7 @@ impl Stack {
             FreeTrapStack::new(
                 range.start as usize..range.end as usize,
                 |_| {}, // Empty callback
-                unsafe { NonNull::new_unchecked(&mut FlowContext::ZERO) },
+                unsafe { NonNull::new_unchecked(&raw mut TRAP_CONTEXT) },
                 fast_handler,
             )
             .unwrap()
@@ -308,62 +490,173 @@ fn clear_bss() {
     }
 }
 
-#[unsafe(no_mangle)]
-#[unsafe(link_section = ".text.entry")]
-extern "C" fn _start(argc: usize, argv: usize) -> ! {
+// 入口只切换到自己的栈再跳到 Rust，前级引导的栈不知道有多大、在哪里，
+// Rust 函数的栈帧在切换前就分配好了，不能在函数中间改 sp
+global_asm!(
+    ".section .text.entry, \"ax\"",
+    ".globl _start",
+    "_start:",
+    "    la sp, {stack} + {stack_size}",
+    "    j {main}",
+    stack = sym HART0_STACK,
+    stack_size = const STACK_SIZE,
+    main = sym rust_main,
+);
+
+// a0 为 hartid，a1 为设备树地址，由 `_start` 原样传过来，设备树只有 payload 用
+#[inline(never)]
+#[cfg_attr(not(feature = "payload"), allow(unused_variables))]
+extern "C" fn rust_main(hartid: usize, fdt: usize) -> ! {
     clear_bss();
-
-    unsafe {
-        asm!(
-            "la sp, {stack} + {stack_size}",
-            stack = sym HART0_STACK,
-            stack_size = const STACK_SIZE,
-        )
+    boot::stage("bss");
+    #[cfg(feature = "alloc")]
+    {
+        heap::init();
+        boot::stage("heap");
     }
 
-    unsafe { PLATFORM.console = Some(Uart16550Wrap::<u32>::new(0x10000000)) };
+    #[cfg(feature = "machine")]
+    console::init(console::PlatformConsole::new(0x10000000));
+    #[cfg(feature = "supervisor")]
+    {
+        supervisor::init();
+        console::init(supervisor::SbiConsole::new());
+    }
+    boot::stage("console");
+    ramlog::init();
 
-    println!("Hello, world!112222233");
+    println!("embassy_app {}", env!("CARGO_PKG_VERSION"));
 
-    // Logger::init().unwrap();
-    // info!("Hello Embassy");
+    #[cfg(feature = "machine")]
+    {
+        pmp::init(cfg!(feature = "pmp-lock"));
+        pmp::dump();
+        boot::stage("pmp");
+    }
+    stack_guard::init(unsafe { HART0_STACK.range() });
+    watchdog::init();
+    println!("reset reason: {:?}", watchdog::reset_reason());
+    boot::stage("watchdog");
 
     time_driver::init();
-    println!("Hello, world!112");
+    boot::stage("time driver");
+    #[cfg(feature = "monitor")]
+    monitor::enter_on_key(Duration::from_millis(500));
+    // 时间戳依赖 time_driver 算出的 CLINT 频率，放在它后面初始化
+    #[cfg(feature = "log")]
+    {
+        log::Logger::init(hartid).unwrap();
+        ::log::info!("Hello Embassy");
+    }
+    plic::init(hartid);
+    // QEMU virt 没有 JH7110 的 GPIO 控制器
+    #[cfg(not(feature = "qemu"))]
+    gpio_irq::init();
+    boot::stage("interrupts");
 
     unsafe { HART0_STACK.load_as_stack() };
-    println!("Hello, world!113");
-
-    unsafe { mtvec::write(fast_trap::trap_entry as _, mtvec::TrapMode::Direct) };
-    println!("Hello, world!114");
-
-    let executor_new = Executor::new();
-    println!("Hello, world!115");
-
-    let executor = EXECUTOR.init(executor_new);
-    println!("Hello, world!116");
-    // unsafe {
-    //     EXECUTOR = Some(Executor::new());
-    // if let Some(executor) = EXECUTOR.as_mut() {
-    executor.run(|spawner| {
-        println!("Hello, world!6");
-        spawner.spawn(run_gpio()).unwrap();
-        spawner.spawn(run_simple()).unwrap()
-    });
-    // }
-    // };
-    // loop {}
+    // 之后可能在中断里打印，控制台不能再关着中断等 UART
+    console::boot_done();
+
+    #[cfg(feature = "machine")]
+    unsafe {
+        mtvec::write(fast_trap::trap_entry as _, mtvec::TrapMode::Direct)
+    };
+    // 线程模式执行器在 wfi 之后靠中断唤醒，定时器和 PLIC 外部中断都要打开 MIE 才会进入处理函数
+    #[cfg(all(feature = "machine", feature = "thread-executor"))]
+    unsafe {
+        mstatus::set_mie()
+    };
+    #[cfg(feature = "supervisor")]
+    unsafe {
+        stvec::write(fast_trap::trap_entry as _, stvec::TrapMode::Direct);
+        sstatus::set_sie();
+    };
+    boot::stage("trap");
+
+    // 跳到 S 态 payload，Embassy 任务由 M 态中断继续驱动
+    #[cfg(feature = "payload")]
+    {
+        spawn_tasks(payload::init_executor(hartid));
+        payload::boot(fdt)
+    }
+
+    #[cfg(feature = "thread-executor")]
+    {
+        let executor = EXECUTOR.init(Executor::new());
+        // unsafe {
+        //     EXECUTOR = Some(Executor::new());
+        // if let Some(executor) = EXECUTOR.as_mut() {
+        executor.run(spawn_tasks);
+        // }
+        // };
+        // loop {}
+    }
+}
+
+fn spawn_tasks(spawner: Spawner) {
+    boot::stage("executor");
+    boot::report();
+    // 通过 task_stats::spawn 派生，统计表里才有任务名
+    task_stats::spawn(&spawner, "console", console::drain_task()).unwrap();
+    task_stats::spawn(&spawner, "stack_guard", stack_guard::monitor()).unwrap();
+    #[cfg(feature = "watchdog")]
+    {
+        let mut wdt = watchdog::Watchdog::jh7110().unwrap();
+        wdt.start(Duration::from_secs(10));
+        task_stats::spawn(
+            &spawner,
+            "watchdog",
+            watchdog::feeder(wdt, Duration::from_secs(2)),
+        )
+        .unwrap();
+    }
+    #[cfg(feature = "long-poll")]
+    long_poll::init();
+    // payload 模式下控制台输入交给 S 态
+    #[cfg(not(feature = "payload"))]
+    task_stats::spawn(&spawner, "shell", shell::run()).unwrap();
+    #[cfg(not(feature = "qemu"))]
+    task_stats::spawn(&spawner, "run_gpio", run_gpio()).unwrap();
+    task_stats::spawn(&spawner, "run_simple", run_simple()).unwrap();
 }
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     use ::riscv::register::*;
+    // 先发完缓冲区里的日志，之后直接同步输出
+    console::panic_flush();
     // error!("Hart {} {info}", current_hartid());
     println!("{info}");
     println!("-----------------------------");
-    println!("mcause:  {:?}", mcause::read().cause());
-    println!("mepc:    {:#018x}", mepc::read());
-    println!("mtval:   {:#018x}", mtval::read());
+    #[cfg(feature = "machine")]
+    {
+        println!("mcause:  {:?}", mcause::read().cause());
+        println!("mepc:    {:#018x}", mepc::read());
+        println!("mtval:   {:#018x}", mtval::read());
+    }
+    #[cfg(feature = "supervisor")]
+    {
+        println!("scause:  {:?}", scause::read().cause());
+        println!("sepc:    {:#018x}", sepc::read());
+        println!("stval:   {:#018x}", stval::read());
+    }
+    stack_guard::report();
+    match boot::last_stage() {
+        Some("executor") => {}
+        Some(stage) => println!("boot:    panicked during boot, last stage {}", stage),
+        None => println!("boot:    panicked before any boot stage"),
+    }
+    #[cfg(feature = "alloc")]
+    heap::report();
+    // 监视器里执行器不再运行，喂狗任务也停了，先停掉看门狗
+    #[cfg(all(feature = "watchdog", feature = "monitor"))]
+    if let Ok(mut watchdog) = watchdog::Watchdog::jh7110() {
+        watchdog.stop();
+    }
+    watchdog::record_panic();
+    #[cfg(feature = "monitor")]
+    monitor::enter();
     println!("-----------------------------");
     println!("System shutdown scheduled due to RustSBI panic");
     // error!("-----------------------------");
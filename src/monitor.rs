@@ -0,0 +1,386 @@
+//! 调试监视器：读写物理地址、打印 CSR、十六进制转储
+//!
+//! 不依赖执行器，输入轮询控制台、输出用 `eprint!` 同步写出，可以在 `_start`
+//! 里执行器启动之前或者 panic handler 里用 [`enter`] 进入。执行器跑起来以后
+//! 命令行的 `mon` 命令把参数转给 [`command`]，命令相同：
+//!
+//! | 命令 | 作用 |
+//! |------|------|
+//! | `r <addr> [b\|h\|w\|d]` | 按宽度读一次，默认 32 位 |
+//! | `w <addr> <value> [b\|h\|w\|d]` | 按宽度写一次 |
+//! | `d <addr> [len]` | 十六进制转储，默认 256 字节 |
+//! | `csr` | 打印陷入相关的 CSR |
+//! | `c` | 退出监视器继续运行 |
+//!
+//! 访问不存在的地址会触发访问异常，和程序里的非法访问一样处理。
+
+#[cfg(not(test))]
+use core::arch::asm;
+
+#[cfg(not(test))]
+use embassy_time::{Duration, Instant};
+
+#[cfg(not(test))]
+use crate::console;
+
+/// 一行最多的字节数
+const LINE_LEN: usize = 80;
+/// `d` 命令默认的长度
+const DEFAULT_DUMP_LEN: usize = 256;
+
+/// 访问宽度
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Width {
+    Byte = 1,
+    Half = 2,
+    Word = 4,
+    Double = 8,
+}
+
+impl Width {
+    /// 解析 `b`/`h`/`w`/`d`，不给时为 32 位
+    pub fn parse(s: Option<&str>) -> Option<Self> {
+        match s {
+            Some("b") => Some(Self::Byte),
+            Some("h") => Some(Self::Half),
+            None | Some("w") => Some(Self::Word),
+            Some("d") => Some(Self::Double),
+            _ => None,
+        }
+    }
+
+    pub const fn bytes(self) -> usize {
+        self as usize
+    }
+}
+
+/// 按宽度读一次，地址必须对齐
+pub fn peek(addr: usize, width: Width) -> Result<u64, &'static str> {
+    if !addr.is_multiple_of(width.bytes()) {
+        return Err("unaligned address");
+    }
+    let value = unsafe {
+        match width {
+            Width::Byte => (addr as *const u8).read_volatile() as u64,
+            Width::Half => (addr as *const u16).read_volatile() as u64,
+            Width::Word => (addr as *const u32).read_volatile() as u64,
+            Width::Double => (addr as *const u64).read_volatile(),
+        }
+    };
+    Ok(value)
+}
+
+/// 按宽度写一次，地址必须对齐，`value` 超出宽度的部分被截掉
+pub fn poke(addr: usize, value: u64, width: Width) -> Result<(), &'static str> {
+    if !addr.is_multiple_of(width.bytes()) {
+        return Err("unaligned address");
+    }
+    unsafe {
+        match width {
+            Width::Byte => (addr as *mut u8).write_volatile(value as u8),
+            Width::Half => (addr as *mut u16).write_volatile(value as u16),
+            Width::Word => (addr as *mut u32).write_volatile(value as u32),
+            Width::Double => (addr as *mut u64).write_volatile(value),
+        }
+    }
+    Ok(())
+}
+
+/// 十六进制转储，每行 16 字节
+///
+/// 按 32 位对齐读取（很多外设寄存器不允许按字节访问），起点向下取整到 4 字节
+#[cfg(not(test))]
+pub fn hexdump(addr: usize, len: usize) {
+    let read = |at: usize| unsafe { (at as *const u32).read_volatile() };
+    dump_lines(addr, len, read, |line, bytes| {
+        eprint!("{:#018x}:", line);
+        for (i, byte) in bytes.iter().enumerate() {
+            if i % 4 == 0 {
+                eprint!(" ");
+            }
+            match byte {
+                Some(byte) => eprint!("{:02x}", byte),
+                None => eprint!("  "),
+            }
+        }
+        eprint!("  |");
+        for byte in bytes {
+            let c = match *byte {
+                None => ' ',
+                Some(byte @ 0x20..=0x7e) => byte as char,
+                Some(_) => '.',
+            };
+            eprint!("{}", c);
+        }
+        eprintln!("|");
+    });
+}
+
+// 按行取出 [`hexdump`] 的内容，`read` 读一个对齐的字，范围外的字节为 `None`；
+// 读内存和打印都由调用方给出，测试时换成假的
+fn dump_lines(
+    addr: usize,
+    len: usize,
+    mut read: impl FnMut(usize) -> u32,
+    mut output: impl FnMut(usize, &[Option<u8>; 16]),
+) {
+    let start = addr & !3;
+    let end = addr.saturating_add(len);
+    let mut line = start & !15;
+    while line < end {
+        let mut bytes = [None; 16];
+        for word in 0..4 {
+            let at = line + word * 4;
+            if at + 3 >= start && at < end {
+                for (i, byte) in read(at).to_le_bytes().into_iter().enumerate() {
+                    if (start..end).contains(&(at + i)) {
+                        bytes[word * 4 + i] = Some(byte);
+                    }
+                }
+            }
+        }
+        output(line, &bytes);
+        // 转储到地址空间末尾时不能回绕到 0
+        match line.checked_add(16) {
+            Some(next) => line = next,
+            None => break,
+        }
+    }
+}
+
+// CSR 编号必须是立即数
+#[cfg(not(test))]
+macro_rules! read_csr {
+    ($name:literal) => {{
+        let value: usize;
+        unsafe { asm!(concat!("csrr {}, ", $name), out(reg) value) };
+        value
+    }};
+}
+
+/// 打印陷入相关的 CSR
+#[cfg(not(test))]
+pub fn dump_csrs() {
+    #[cfg(feature = "machine")]
+    let csrs = [
+        ("mstatus", read_csr!("mstatus")),
+        ("mie", read_csr!("mie")),
+        ("mip", read_csr!("mip")),
+        ("mtvec", read_csr!("mtvec")),
+        ("mcause", read_csr!("mcause")),
+        ("mepc", read_csr!("mepc")),
+        ("mtval", read_csr!("mtval")),
+        ("mscratch", read_csr!("mscratch")),
+    ];
+    #[cfg(feature = "supervisor")]
+    let csrs = [
+        ("sstatus", read_csr!("sstatus")),
+        ("sie", read_csr!("sie")),
+        ("sip", read_csr!("sip")),
+        ("stvec", read_csr!("stvec")),
+        ("scause", read_csr!("scause")),
+        ("sepc", read_csr!("sepc")),
+        ("stval", read_csr!("stval")),
+        ("sscratch", read_csr!("sscratch")),
+    ];
+    for (name, value) in csrs {
+        eprintln!("{:9}{:#018x}", name, value);
+    }
+}
+
+/// 解析十进制或 `0x` 开头的十六进制数，命令行和监视器共用
+///
+/// 总是按 64 位解析，RV32 上也能写 64 位的值，地址和长度再用 [`parse_usize`] 截取。
+pub fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// 解析地址或长度，超出 `usize` 时返回 `None`
+pub fn parse_usize(s: &str) -> Option<usize> {
+    parse_number(s).and_then(|n| usize::try_from(n).ok())
+}
+
+/// 执行一条监视器命令，`args[0]` 是命令名
+#[cfg(not(test))]
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    let arg = |i: usize| args.get(i).copied();
+    match arg(0) {
+        Some("r") => {
+            let addr = arg(1).and_then(parse_usize).ok_or("invalid address")?;
+            let width = Width::parse(arg(2)).ok_or("width must be b, h, w or d")?;
+            let value = peek(addr, width)?;
+            eprintln!("{:#x}: {:#0w$x}", addr, value, w = width.bytes() * 2 + 2);
+        }
+        Some("w") => {
+            let addr = arg(1).and_then(parse_usize).ok_or("invalid address")?;
+            let value = arg(2).and_then(parse_number).ok_or("invalid value")?;
+            let width = Width::parse(arg(3)).ok_or("width must be b, h, w or d")?;
+            poke(addr, value, width)?;
+        }
+        Some("d") => {
+            let addr = arg(1).and_then(parse_usize).ok_or("invalid address")?;
+            let len = match arg(2) {
+                Some(len) => parse_usize(len).ok_or("invalid length")?,
+                None => DEFAULT_DUMP_LEN,
+            };
+            hexdump(addr, len);
+        }
+        Some("csr") => dump_csrs(),
+        _ => return Err("usage: r <addr> [w] | w <addr> <value> [w] | d <addr> [len] | csr"),
+    }
+    Ok(())
+}
+
+// 阻塞读一行，只支持退格
+#[cfg(not(test))]
+fn read_line(buf: &mut [u8; LINE_LEN]) -> &str {
+    let mut len = 0;
+    loop {
+        let mut byte = [0];
+        if console::read(&mut byte) == 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+        match byte[0] {
+            b'\r' | b'\n' => {
+                eprintln!();
+                break;
+            }
+            0x08 | 0x7f if len > 0 => {
+                len -= 1;
+                eprint!("\x08 \x08");
+            }
+            byte @ 0x20..=0x7e if len < LINE_LEN => {
+                buf[len] = byte;
+                len += 1;
+                eprint!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
+    core::str::from_utf8(&buf[..len]).unwrap_or("")
+}
+
+/// 进入监视器，输入 `c` 时返回
+#[cfg(not(test))]
+pub fn enter() {
+    eprintln!("monitor: r/w/d/csr, c to continue");
+    let mut buf = [0; LINE_LEN];
+    loop {
+        eprint!("mon> ");
+        let mut args = [""; 4];
+        let mut argc = 0;
+        for word in read_line(&mut buf)
+            .split_ascii_whitespace()
+            .take(args.len())
+        {
+            args[argc] = word;
+            argc += 1;
+        }
+        match args[..argc] {
+            [] => {}
+            ["c"] => return,
+            _ => {
+                if let Err(error) = command(&args[..argc]) {
+                    eprintln!("error: {}", error);
+                }
+            }
+        }
+    }
+}
+
+/// 启动时等待 `wait`，期间控制台有输入就进入监视器，需要 time_driver 已经初始化
+#[cfg(not(test))]
+pub fn enter_on_key(wait: Duration) {
+    eprintln!("monitor: press any key within {} ms", wait.as_millis());
+    let deadline = Instant::now() + wait;
+    while Instant::now() < deadline {
+        let mut byte = [0];
+        if console::read(&mut byte) != 0 {
+            enter();
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn parse_numbers() {
+        assert_eq!(parse_number("42"), Some(42));
+        assert_eq!(parse_number("0x10000000"), Some(0x1000_0000));
+        assert_eq!(parse_number("0XfF"), Some(0xff));
+        assert_eq!(parse_number("0xffffffffffffffff"), Some(u64::MAX));
+        assert_eq!(parse_number("0x10000000000000000"), None);
+        assert_eq!(parse_number("18446744073709551616"), None);
+        assert_eq!(parse_number(""), None);
+        assert_eq!(parse_number("0x"), None);
+        assert_eq!(parse_number("-1"), None);
+        assert_eq!(parse_number("12k"), None);
+    }
+
+    #[test]
+    fn parse_usize_bounds() {
+        assert_eq!(parse_usize("0x1000"), Some(0x1000));
+        let max = usize::MAX as u64;
+        assert_eq!(parse_usize(&std::format!("{:#x}", max)), Some(usize::MAX));
+        if max < u64::MAX {
+            assert_eq!(parse_usize(&std::format!("{:#x}", max + 1)), None);
+        }
+    }
+
+    type Line = (usize, [Option<u8>; 16]);
+
+    // 记下读过的字和输出的行，读到的值就是地址本身
+    fn dump(addr: usize, len: usize) -> (Vec<usize>, Vec<Line>) {
+        let mut reads = Vec::new();
+        let mut lines = Vec::new();
+        dump_lines(
+            addr,
+            len,
+            |at| {
+                reads.push(at);
+                at as u32
+            },
+            |line, bytes| lines.push((line, *bytes)),
+        );
+        (reads, lines)
+    }
+
+    #[test]
+    fn dump_partial_lines() {
+        let (reads, lines) = dump(0x1006, 0x10);
+        // 起点向下取整到字，只读覆盖范围的字
+        assert_eq!(reads, [0x1004, 0x1008, 0x100c, 0x1010, 0x1014]);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].0, 0x1000);
+        // 起点所在的字整个打印出来
+        assert_eq!(lines[0].1[..4], [None; 4]);
+        assert_eq!(lines[0].1[4..6], [Some(0x04), Some(0x10)]);
+        assert_eq!(lines[1].0, 0x1010);
+        assert_eq!(lines[1].1[5], Some(0x10));
+        assert_eq!(lines[1].1[6..], [None; 10]);
+    }
+
+    #[test]
+    fn dump_stops_at_top_of_memory() {
+        let (reads, lines) = dump(usize::MAX - 0x1f, 0x100);
+        // 长度超出地址空间时截到末尾，不回绕到 0
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].0, usize::MAX - 0x1f);
+        assert_eq!(lines[1].0, usize::MAX - 0xf);
+        assert_eq!(reads.len(), 8);
+        assert_eq!(reads.last(), Some(&(usize::MAX - 3)));
+        // 结束地址是开区间，最后一个字节取不到
+        assert_eq!(lines[1].1[15], None);
+        assert!(lines[1].1[..15].iter().all(Option::is_some));
+    }
+}
@@ -0,0 +1,110 @@
+//! 作为 M 态固件启动 S 态 payload（U-Boot 或内核），Embassy 任务继续在 M 态运行
+//!
+//! 只有一个 hart：跳到 payload 之后 M 态不再有线程上下文，所以执行器换成
+//! `raw::Executor`。任务被唤醒时 `__pender` 通过 CLINT 的 msip 给自己发软件中断，
+//! 在 [`crate::handler::msoft_handler`] 里轮询执行器。
+//!
+//! payload 默认由上一级引导程序放在 [`PAYLOAD_BASE`]；编译时设置环境变量
+//! `PAYLOAD=<镜像路径>` 则把镜像嵌进固件，启动时复制过去。
+
+use core::{arch::asm, ptr::null_mut};
+
+use embassy_executor::{Spawner, raw};
+use riscv::register::{mepc, mie, mstatus};
+use static_cell::StaticCell;
+
+use crate::{CLINT, pmp};
+
+/// payload 的加载和入口地址
+pub const PAYLOAD_BASE: usize = 0x8020_0000;
+/// payload 不能覆盖从 0x80400000 开始的固件
+pub const PAYLOAD_MAX_SIZE: usize = 0x20_0000;
+
+// 委托给 S 态的中断：SSI、STI、SEI
+const MIDELEG: usize = (1 << 1) | (1 << 5) | (1 << 9);
+// 委托给 S 态的异常：指令不对齐、非法指令、断点、读写不对齐、U 态 ecall 以及三种缺页
+const MEDELEG: usize = (1 << 0)
+    | (1 << 2)
+    | (1 << 3)
+    | (1 << 4)
+    | (1 << 6)
+    | (1 << 8)
+    | (1 << 12)
+    | (1 << 13)
+    | (1 << 15);
+
+#[cfg(payload_image)]
+static PAYLOAD_IMAGE: &[u8] = include_bytes!(env!("PAYLOAD"));
+
+static mut BOOT_HART: usize = 0;
+static EXECUTOR: StaticCell<raw::Executor> = StaticCell::new();
+static mut EXECUTOR_REF: Option<&'static raw::Executor> = None;
+
+/// 启动 hart 的编号，由 `_start` 的 a0 传入
+pub fn boot_hart() -> usize {
+    unsafe { BOOT_HART }
+}
+
+/// 创建中断驱动的执行器，返回用于派生任务的 `Spawner`
+pub fn init_executor(hartid: usize) -> Spawner {
+    unsafe { BOOT_HART = hartid };
+    let executor = EXECUTOR.init(raw::Executor::new(null_mut()));
+    unsafe { EXECUTOR_REF = Some(executor) };
+    executor.spawner()
+}
+
+/// 轮询一次执行器，只能在 M 态陷入上下文里调用
+pub fn poll_executor() {
+    if let Some(executor) = unsafe { EXECUTOR_REF } {
+        unsafe { executor.poll() };
+    }
+}
+
+#[unsafe(export_name = "__pender")]
+fn pender(_context: *mut ()) {
+    unsafe { CLINT.set_msip(BOOT_HART) };
+}
+
+fn load_image() {
+    #[cfg(payload_image)]
+    {
+        assert!(
+            PAYLOAD_IMAGE.len() <= PAYLOAD_MAX_SIZE,
+            "payload image too large"
+        );
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                PAYLOAD_IMAGE.as_ptr(),
+                PAYLOAD_BASE as *mut u8,
+                PAYLOAD_IMAGE.len(),
+            )
+        };
+    }
+}
+
+/// 设置委托并 `mret` 到 payload，a0 为 hartid，a1 为设备树地址
+pub fn boot(fdt: usize) -> ! {
+    load_image();
+    pmp::allow_supervisor();
+    println!("payload: jumping to {:#x}, fdt {:#x}", PAYLOAD_BASE, fdt);
+    unsafe {
+        asm!("csrw mideleg, {}", in(reg) MIDELEG);
+        asm!("csrw medeleg, {}", in(reg) MEDELEG);
+        // S/U 态可以读 cycle、time、instret
+        asm!("csrw mcounteren, {}", in(reg) 0b111);
+        // M 态自己的定时器和软件中断，跑在 S 态时 M 态中断总是打开的
+        mie::set_mtimer();
+        mie::set_msoft();
+        // 开始前先处理一次已经就绪的任务
+        CLINT.set_msip(BOOT_HART);
+        mstatus::set_mpp(mstatus::MPP::Supervisor);
+        mepc::write(PAYLOAD_BASE);
+        asm!("fence.i");
+        asm!(
+            "mret",
+            in("a0") BOOT_HART,
+            in("a1") fdt,
+            options(noreturn),
+        )
+    }
+}
@@ -0,0 +1,129 @@
+//! PLIC 外部中断控制器
+//!
+//! 只使用启动 hart 当前特权级的一个上下文，由 [`init`] 按 hart 号和平台算出。
+//! 各个驱动通过 [`register`] 挂上自己的处理函数，
+//! 外部中断到来时由 [`handle_interrupt`] 逐个 claim 并分发。
+
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use critical_section::{Mutex, with};
+
+/// JH7110 / QEMU virt 的 PLIC 基地址
+pub const PLIC_BASE: usize = 0x0c00_0000;
+/// 支持的中断源数量（JH7110 为 136）
+pub const MAX_IRQS: usize = 137;
+
+const PRIORITY: usize = 0x0;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4;
+
+// 启动 hart 使用的上下文
+static HART_CONTEXT: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLERS: Mutex<RefCell<[Option<fn()>; MAX_IRQS]>> =
+    Mutex::new(RefCell::new([None; MAX_IRQS]));
+
+fn reg(offset: usize) -> *mut u32 {
+    (PLIC_BASE + offset) as *mut u32
+}
+
+/// hart 在当前特权级的上下文号
+///
+/// QEMU virt 上每个 hart 依次占 M、S 两个上下文；JH7110 的 hart 0 是没有 S 态的
+/// S7 监控核，只占上下文 0，U74 的 hart n 的 M 态上下文从 2n-1 开始。
+const fn context_of(hartid: usize) -> usize {
+    let machine = if cfg!(feature = "qemu") {
+        hartid * 2
+    } else if hartid == 0 {
+        0
+    } else {
+        hartid * 2 - 1
+    };
+    if cfg!(feature = "supervisor") {
+        machine + 1
+    } else {
+        machine
+    }
+}
+
+fn context_reg(offset: usize) -> *mut u32 {
+    reg(CONTEXT + HART_CONTEXT.load(Ordering::Relaxed) * CONTEXT_STRIDE + offset)
+}
+
+/// 选定启动 hart 的上下文，阈值清零并打开外部中断
+pub fn init(hartid: usize) {
+    HART_CONTEXT.store(context_of(hartid), Ordering::Relaxed);
+    unsafe {
+        context_reg(CONTEXT_THRESHOLD).write_volatile(0);
+    }
+    #[cfg(feature = "machine")]
+    unsafe {
+        riscv::register::mie::set_mext();
+    }
+    #[cfg(feature = "supervisor")]
+    unsafe {
+        riscv::register::sie::set_sext();
+    }
+}
+
+/// 设置中断源优先级，0 表示永不触发
+pub fn set_priority(irq: usize, priority: u32) {
+    unsafe { reg(PRIORITY + irq * 4).write_volatile(priority) };
+}
+
+fn enable_reg(irq: usize) -> (*mut u32, u32) {
+    let context = HART_CONTEXT.load(Ordering::Relaxed);
+    let addr = reg(ENABLE + context * ENABLE_STRIDE + (irq / 32) * 4);
+    (addr, 1 << (irq % 32))
+}
+
+pub fn enable(irq: usize) {
+    let (addr, mask) = enable_reg(irq);
+    unsafe { addr.write_volatile(addr.read_volatile() | mask) };
+}
+
+pub fn disable(irq: usize) {
+    let (addr, mask) = enable_reg(irq);
+    unsafe { addr.write_volatile(addr.read_volatile() & !mask) };
+}
+
+/// 挂上中断处理函数，设置优先级并使能该中断源
+pub fn register(irq: usize, handler: fn()) {
+    with(|cs| HANDLERS.borrow_ref_mut(cs)[irq] = Some(handler));
+    set_priority(irq, 1);
+    enable(irq);
+}
+
+fn claim() -> u32 {
+    unsafe { context_reg(CONTEXT_CLAIM).read_volatile() }
+}
+
+fn complete(irq: u32) {
+    unsafe { context_reg(CONTEXT_CLAIM).write_volatile(irq) };
+}
+
+/// 外部中断入口，在陷入处理里调用
+pub fn handle_interrupt() {
+    loop {
+        let irq = claim();
+        if irq == 0 {
+            break;
+        }
+        let handler = with(|cs| HANDLERS.borrow_ref(cs).get(irq as usize).copied().flatten());
+        match handler {
+            Some(handler) => handler(),
+            None => {
+                println!("plic: unexpected irq {}", irq);
+                disable(irq as usize);
+            }
+        }
+        complete(irq);
+    }
+}
@@ -0,0 +1,335 @@
+//! 物理内存保护（PMP）配置
+//!
+//! 按链接脚本的布局设置 PMP：
+//!
+//! | 项 | 区域           | 权限 |
+//! |----|----------------|------|
+//! | 0  | 栈底保护区     | 无   |
+//! | 1  | `.text` 起点   | 关闭（只作为下一项 TOR 的基址） |
+//! | 2  | `.text`        | R-X  |
+//! | 3  | `.rodata`      | R--  |
+//! | 4  | `.data`..`.heap` | RW- |
+//! | 5  | `.text` 起点   | 关闭（`payload`，只作为下一项 TOR 的基址） |
+//! | 6  | 整个固件       | 无（`payload`） |
+//! | 7  | 整个地址空间   | RWX（`payload`） |
+//!
+//! M 态只受加锁的表项约束，所以栈底保护区总是加锁；其余表项是否加锁由
+//! [`init`] 的参数决定（`pmp-lock` feature），不加锁时只约束 S/U 态。
+//! 访问异常由 [`report_fault`] 解码成落在哪个区域的报告。
+
+use core::arch::asm;
+#[cfg(feature = "payload")]
+use core::ops::Range;
+
+use riscv::interrupt::Exception;
+
+/// 本模块使用的 PMP 表项数
+pub const ENTRIES: usize = 8;
+
+pub const PERM_NONE: u8 = 0;
+pub const PERM_R: u8 = 1 << 0;
+pub const PERM_W: u8 = 1 << 1;
+pub const PERM_X: u8 = 1 << 2;
+const CFG_LOCK: u8 = 1 << 7;
+
+/// pmpcfg 中 A 字段的地址匹配模式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum AddressMode {
+    Off = 0,
+    Tor = 1,
+    Na4 = 2,
+    Napot = 3,
+}
+
+/// 一个已配置的保护区域，用于访问异常时反查
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Region {
+    pub name: &'static str,
+    pub start: usize,
+    pub end: usize,
+    pub perm: u8,
+    pub locked: bool,
+}
+
+impl Region {
+    fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+static mut REGIONS: [Option<Region>; ENTRIES] = [None; ENTRIES];
+static mut PRESENT: bool = false;
+
+fn write_pmpaddr(index: usize, value: usize) {
+    // CSR 编号必须是立即数，只能逐个展开
+    macro_rules! write_pmpaddr_n {
+        ($($n:literal),*) => {
+            match index {
+                $($n => unsafe { asm!(concat!("csrw pmpaddr", $n, ", {}"), in(reg) value) },)*
+                _ => unreachable!(),
+            }
+        };
+    }
+    write_pmpaddr_n!(0, 1, 2, 3, 4, 5, 6, 7)
+}
+
+fn read_pmpaddr0() -> usize {
+    let value: usize;
+    unsafe { asm!("csrr {}, pmpaddr0", out(reg) value) };
+    value
+}
+
+// 每个 pmpcfg 寄存器容纳的配置字节数：RV64 只有偶数号 pmpcfg，RV32 是 pmpcfg0、pmpcfg1
+#[cfg(target_pointer_width = "64")]
+const CFG_PER_REG: usize = 8;
+#[cfg(target_pointer_width = "32")]
+const CFG_PER_REG: usize = 4;
+
+fn read_pmpcfg(reg: usize) -> usize {
+    let value: usize;
+    match reg {
+        0 => unsafe { asm!("csrr {}, pmpcfg0", out(reg) value) },
+        #[cfg(target_pointer_width = "32")]
+        1 => unsafe { asm!("csrr {}, pmpcfg1", out(reg) value) },
+        _ => unreachable!(),
+    }
+    value
+}
+
+fn write_pmpcfg(reg: usize, value: usize) {
+    match reg {
+        0 => unsafe { asm!("csrw pmpcfg0, {}", in(reg) value) },
+        #[cfg(target_pointer_width = "32")]
+        1 => unsafe { asm!("csrw pmpcfg1, {}", in(reg) value) },
+        _ => unreachable!(),
+    }
+}
+
+/// 设置第 `index` 项的配置字节，其余表项保持不变
+fn write_cfg(index: usize, cfg: u8) {
+    let reg = index / CFG_PER_REG;
+    let shift = (index % CFG_PER_REG) * 8;
+    let value = (read_pmpcfg(reg) & !(0xff << shift)) | ((cfg as usize) << shift);
+    write_pmpcfg(reg, value);
+}
+
+/// 探测是否实现了 PMP：没有实现时 pmpaddr0 读回为 0
+fn probe() -> bool {
+    let old = read_pmpaddr0();
+    write_pmpaddr(0, usize::MAX >> 10);
+    let present = read_pmpaddr0() != 0;
+    write_pmpaddr(0, old);
+    present
+}
+
+/// 配置一项 PMP 并记录到区域表
+///
+/// `Napot` 模式要求 `start` 按大小自然对齐、大小是 2 的幂且至少 8 字节；
+/// `Tor` 模式的起点由前一项的 pmpaddr 决定，这里的 `start` 只用于反查。
+pub fn set_entry(index: usize, region: Region, mode: AddressMode) {
+    assert!(index < ENTRIES);
+    let addr = match mode {
+        AddressMode::Napot => {
+            let size = region.end - region.start;
+            (region.start >> 2) | ((size >> 3) - 1)
+        }
+        _ => region.end >> 2,
+    };
+    let mut cfg = (region.perm & (PERM_R | PERM_W | PERM_X)) | ((mode as u8) << 3);
+    if region.locked {
+        cfg |= CFG_LOCK;
+    }
+    write_pmpaddr(index, addr);
+    write_cfg(index, cfg);
+    if mode != AddressMode::Off {
+        unsafe { REGIONS[index] = Some(region) };
+    }
+}
+
+/// 按链接脚本布局配置 PMP，返回是否实现了 PMP
+///
+/// `payload` 模式下不加锁的表项只约束 S 态，固件区域对 S 态一律禁止访问。
+pub fn init(locked: bool) -> bool {
+    unsafe extern "C" {
+        fn start_text();
+        fn end_text();
+        fn end_rodata();
+        fn end_heap();
+        fn stack_guard_start();
+        fn stack_guard_end();
+    }
+    if !probe() {
+        return false;
+    }
+    unsafe { PRESENT = true };
+    let perm = |perm| {
+        if cfg!(feature = "payload") && !locked {
+            PERM_NONE
+        } else {
+            perm
+        }
+    };
+
+    set_entry(
+        0,
+        Region {
+            name: "stack guard",
+            start: stack_guard_start as usize,
+            end: stack_guard_end as usize,
+            perm: PERM_NONE,
+            locked: true,
+        },
+        AddressMode::Napot,
+    );
+    // 第 1 项只提供 `.text` 的 TOR 基址
+    write_pmpaddr(1, start_text as usize >> 2);
+    write_cfg(1, AddressMode::Off as u8);
+    set_entry(
+        2,
+        Region {
+            name: ".text",
+            start: start_text as usize,
+            end: end_text as usize,
+            perm: perm(PERM_R | PERM_X),
+            locked,
+        },
+        AddressMode::Tor,
+    );
+    set_entry(
+        3,
+        Region {
+            name: ".rodata",
+            start: end_text as usize,
+            end: end_rodata as usize,
+            perm: perm(PERM_R),
+            locked,
+        },
+        AddressMode::Tor,
+    );
+    set_entry(
+        4,
+        Region {
+            name: ".data/.bss/.heap",
+            start: end_rodata as usize,
+            end: end_heap as usize,
+            perm: perm(PERM_R | PERM_W),
+            locked,
+        },
+        AddressMode::Tor,
+    );
+    true
+}
+
+/// 固件占用的内存（`.text` 到堆的末尾），S 态 payload 不能访问
+#[cfg(feature = "payload")]
+pub fn firmware_range() -> Range<usize> {
+    unsafe extern "C" {
+        fn start_text();
+        fn end_heap();
+    }
+    start_text as usize..end_heap as usize
+}
+
+/// 最后一项放开整个地址空间，让 S 态 payload 能访问内存和外设，前一项把固件整个挡住
+///
+/// 没有任何表项匹配时 S 态访问会失败，所以启动 payload 前必须调用。
+#[cfg(feature = "payload")]
+pub fn allow_supervisor() {
+    if !is_present() {
+        return;
+    }
+    let firmware = firmware_range();
+    // 第 5 项只提供第 6 项的 TOR 基址
+    write_pmpaddr(5, firmware.start >> 2);
+    write_cfg(5, AddressMode::Off as u8);
+    set_entry(
+        6,
+        Region {
+            name: "firmware",
+            start: firmware.start,
+            end: firmware.end,
+            perm: PERM_NONE,
+            locked: false,
+        },
+        AddressMode::Tor,
+    );
+    let index = ENTRIES - 1;
+    // NAPOT 全 1 表示整个地址空间
+    write_pmpaddr(index, usize::MAX);
+    write_cfg(
+        index,
+        PERM_R | PERM_W | PERM_X | ((AddressMode::Napot as u8) << 3),
+    );
+    unsafe {
+        REGIONS[index] = Some(Region {
+            name: "payload/mmio",
+            start: 0,
+            end: usize::MAX,
+            perm: PERM_R | PERM_W | PERM_X,
+            locked: false,
+        })
+    };
+}
+
+/// 是否实现并启用了 PMP
+pub fn is_present() -> bool {
+    unsafe { PRESENT }
+}
+
+/// 查找 `addr` 命中的区域，按表项优先级（编号小的优先）
+pub fn find_region(addr: usize) -> Option<Region> {
+    unsafe { REGIONS.iter().flatten().find(|r| r.contains(addr)).copied() }
+}
+
+/// 打印已配置的区域
+pub fn dump() {
+    for (index, region) in unsafe { REGIONS.iter().enumerate() } {
+        if let Some(region) = region {
+            println!(
+                "pmp{}:    {:#018x}..{:#018x} {} {:8} {}",
+                index,
+                region.start,
+                region.end,
+                perm_str(region.perm),
+                lock_str(region.locked),
+                region.name
+            );
+        }
+    }
+}
+
+fn perm_str(perm: u8) -> &'static str {
+    const NAMES: [&str; 8] = ["---", "r--", "-w-", "rw-", "--x", "r-x", "-wx", "rwx"];
+    NAMES[(perm & 7) as usize]
+}
+
+fn lock_str(locked: bool) -> &'static str {
+    if locked { "locked" } else { "unlocked" }
+}
+
+/// 解码访问异常，打印访问类型、地址以及违反的区域
+pub fn report_fault(exception: Exception, addr: usize) {
+    let access = match exception {
+        Exception::InstructionFault => "instruction fetch",
+        Exception::LoadFault => "load",
+        Exception::StoreFault => "store/AMO",
+        _ => "unknown access",
+    };
+    println!("-----------------------------");
+    println!("pmp:     {} access fault at {:#018x}", access, addr);
+    match find_region(addr) {
+        Some(region) => println!(
+            "pmp:     violates region {} ({:#x}..{:#x}, {} {})",
+            region.name,
+            region.start,
+            region.end,
+            perm_str(region.perm),
+            lock_str(region.locked)
+        ),
+        None if is_present() => println!("pmp:     address is outside all configured regions"),
+        None => println!("pmp:     PMP not present, fault comes from the bus"),
+    }
+}
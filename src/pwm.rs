@@ -0,0 +1,179 @@
+//! JH7110 PWM（OpenCores PTC），8 个通道，实现 `embedded_hal::pwm::SetDutyCycle`
+//!
+//! 每个通道的计数器按 APB 时钟递增，计到 LRC 回零，HRC 决定高电平的长度，
+//! 参考 Linux pwm-starfive。通道 4~7 的寄存器在基地址 + 0x8000 处。
+//! 波形由硬件产生，设置好之后不需要任务参与。
+
+use core::convert::Infallible;
+
+use embedded_hal::pwm::{ErrorType, SetDutyCycle};
+
+use crate::{
+    clock::{self, Crg, SYSCLK_PWM_APB, SYSRST_PWM_APB},
+    gpio::{Alternate, Function, Pin},
+};
+
+const PWM_BASE: usize = 0x120d_0000;
+pub const CHANNELS: u8 = 8;
+
+// 读不到时钟频率时按 APB0 的默认值计算
+const DEFAULT_CLK_HZ: u64 = 49_500_000;
+
+/// 通道 0 的输出信号，通道 n 为 GPOUT_SYS_PWM_CHANNEL0 + n
+pub const GPOUT_SYS_PWM_CHANNEL0: u32 = 102;
+
+const PTC_CNTR: usize = 0x0;
+const PTC_HRC: usize = 0x4;
+const PTC_LRC: usize = 0x8;
+const PTC_CTRL: usize = 0xc;
+
+const CTRL_EN: u32 = 1 << 0;
+const CTRL_OE: u32 = 1 << 3;
+const CTRL_CNTRRST: u32 = 1 << 7;
+
+fn channel_base(channel: u8) -> usize {
+    let channel = channel as usize;
+    if channel < 4 {
+        PWM_BASE + channel * 0x10
+    } else {
+        PWM_BASE + 0x8000 + (channel % 4) * 0x10
+    }
+}
+
+/// PWM 控制器单例，用来分配通道
+pub struct Pwm {
+    taken: u8,
+    clk_hz: u64,
+}
+
+static mut PWM_TAKEN: bool = false;
+
+impl Pwm {
+    /// 获取 PWM 控制器，打开时钟并释放复位，只有第一次调用返回 `Some`
+    ///
+    /// 等复位释放超时也返回 `None`，这时控制器没有被占用，可以再试。
+    pub fn take() -> Option<Self> {
+        let taken = critical_section::with(|_| unsafe {
+            if PWM_TAKEN {
+                true
+            } else {
+                PWM_TAKEN = true;
+                false
+            }
+        });
+        if taken {
+            return None;
+        }
+        if clock::bring_up(Crg::Sys, &[SYSCLK_PWM_APB], &[SYSRST_PWM_APB]).is_err() {
+            unsafe { PWM_TAKEN = false };
+            return None;
+        }
+        let clk_hz = clock::sys_rate(SYSCLK_PWM_APB)
+            .filter(|&hz| hz != 0)
+            .unwrap_or(DEFAULT_CLK_HZ);
+        Some(Self { taken: 0, clk_hz })
+    }
+
+    /// 把 `channel` 接到管脚 `P` 上，按 `frequency` 输出占空比为 0 的波形，
+    /// 同一个通道只能取一次
+    pub fn channel<const P: u32, MODE>(
+        &mut self,
+        channel: u8,
+        pin: Pin<P, MODE>,
+        frequency: u32,
+    ) -> Option<Channel<P>> {
+        if channel >= CHANNELS || self.taken & (1 << channel) != 0 {
+            return None;
+        }
+        self.taken |= 1 << channel;
+        let pin = pin.into_function(Function::output(GPOUT_SYS_PWM_CHANNEL0 + channel as u32));
+        let mut channel = Channel {
+            base: channel_base(channel),
+            clk_hz: self.clk_hz,
+            period: 0,
+            duty: 0,
+            _pin: pin,
+        };
+        channel.set_frequency(frequency);
+        Some(channel)
+    }
+}
+
+/// 接在管脚 `P` 上的 PWM 通道
+pub struct Channel<const P: u32> {
+    base: usize,
+    clk_hz: u64,
+    // 一个周期的计数值
+    period: u32,
+    // 高电平的计数值
+    duty: u32,
+    _pin: Pin<P, Alternate>,
+}
+
+impl<const P: u32> Channel<P> {
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) }
+    }
+
+    /// 修改频率，保持占空比不变
+    pub fn set_frequency(&mut self, frequency: u32) {
+        let period = (self.clk_hz / frequency.max(1) as u64).clamp(1, u32::MAX as u64) as u32;
+        let duty = match self.period {
+            0 => 0,
+            old => (self.duty as u64 * period as u64 / old as u64) as u32,
+        };
+        self.period = period;
+        self.duty = duty;
+        self.write(PTC_CTRL, 0);
+        self.write(PTC_LRC, period);
+        self.write(PTC_HRC, duty);
+        // 计数器清零后重新开始
+        self.write(PTC_CTRL, CTRL_CNTRRST);
+        self.write(PTC_CNTR, 0);
+        self.write(PTC_CTRL, CTRL_EN | CTRL_OE);
+    }
+
+    /// 当前实际输出的频率
+    pub fn frequency(&self) -> u32 {
+        (self.clk_hz / self.period as u64) as u32
+    }
+
+    /// 按微秒设置高电平宽度，舵机一般为 1000~2000us
+    pub fn set_pulse_us(&mut self, us: u32) {
+        let duty = (self.clk_hz * us as u64 / 1_000_000).min(self.period as u64) as u32;
+        self.set_duty(duty);
+    }
+
+    fn set_duty(&mut self, duty: u32) {
+        self.duty = duty;
+        self.write(PTC_HRC, duty);
+    }
+
+    /// 停止输出
+    pub fn disable(&mut self) {
+        self.write(PTC_CTRL, 0);
+    }
+
+    /// 重新开始输出
+    pub fn enable(&mut self) {
+        self.write(PTC_CTRL, CTRL_EN | CTRL_OE);
+    }
+}
+
+impl<const P: u32> ErrorType for Channel<P> {
+    type Error = Infallible;
+}
+
+impl<const P: u32> SetDutyCycle for Channel<P> {
+    // 周期计数值超过 u16 时按比例换算
+    fn max_duty_cycle(&self) -> u16 {
+        self.period.min(u16::MAX as u32) as u16
+    }
+
+    fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+        let max = self.max_duty_cycle() as u64;
+        let duty = (duty as u64).min(max) * self.period as u64 / max;
+        self.set_duty(duty as u32);
+        Ok(())
+    }
+}
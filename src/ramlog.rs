@@ -0,0 +1,252 @@
+//! 热复位后还能读到的日志环形缓冲区
+//!
+//! 控制台输出同时写进 `.noinit` 段里的一块环形缓冲区。板子卡死被看门狗复位、
+//! 或者按了复位键之后内存内容还在，下次启动时 [`init`] 检查头部的魔数和 CRC，
+//! 有效就在新的启动信息之前打印上一次的最后几行。上电时内存是随机内容，
+//! CRC 对不上，直接当作没有记录。
+//!
+//! CRC 只保护头部（写入位置），数据本身不校验：复位可能发生在写了一半的时候，
+//! 最后一行不完整是正常的。缓冲区本身的读写和平台无关，测试在主机上运行
+//! （`scripts/host-test.sh`）。
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(not(test))]
+use crate::console;
+
+/// 缓冲区大小，必须是 2 的幂
+const SIZE: usize = 8192;
+/// 启动时打印上一次日志的行数
+const DUMP_LINES: usize = 32;
+const MAGIC: u32 = 0x524c_4f47;
+
+#[repr(C)]
+struct Header {
+    magic: u32,
+    size: u32,
+    // 写入计数，取模后是下标；写满后保持在 SIZE..2*SIZE 之间
+    head: u32,
+    crc: u32,
+}
+
+impl Header {
+    fn checksum(&self) -> u32 {
+        let mut crc = !0;
+        for word in [self.magic, self.size, self.head] {
+            crc = crc32_update(crc, &word.to_le_bytes());
+        }
+        !crc
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == MAGIC && self.size == SIZE as u32 && self.crc == self.checksum()
+    }
+}
+
+#[repr(C)]
+struct RamLog {
+    header: Header,
+    buf: [u8; SIZE],
+}
+
+impl RamLog {
+    const fn new() -> Self {
+        Self {
+            header: Header {
+                magic: 0,
+                size: 0,
+                head: 0,
+                crc: 0,
+            },
+            buf: [0; SIZE],
+        }
+    }
+
+    // 清空并写上有效的头部
+    fn reset(&mut self) {
+        self.header = Header {
+            magic: MAGIC,
+            size: SIZE as u32,
+            head: 0,
+            crc: 0,
+        };
+        self.header.crc = self.header.checksum();
+    }
+
+    // 缓冲区里保存的字节数
+    fn len(&self) -> usize {
+        (self.header.head as usize).min(SIZE)
+    }
+
+    // 按写入顺序的第 `i` 个字节
+    fn at(&self, i: usize) -> u8 {
+        let head = self.header.head as usize;
+        self.buf[(head - self.len() + i) % SIZE]
+    }
+
+    // 最后 `lines` 行的起点，从末尾往前数换行，末尾的换行不算一行
+    fn last_lines(&self, lines: usize) -> usize {
+        let mut count = 0;
+        for i in (0..self.len().saturating_sub(1)).rev() {
+            if self.at(i) == b'\n' {
+                count += 1;
+                if count == lines {
+                    return i + 1;
+                }
+            }
+        }
+        0
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        let mut head = self.header.head as usize;
+        // 比缓冲区还长时只保留最后一段
+        let skip = bytes.len().saturating_sub(SIZE);
+        for &byte in &bytes[skip..] {
+            self.buf[head % SIZE] = byte;
+            head += 1;
+        }
+        // 写满之后只需要记住下标，计数保持在 SIZE..2*SIZE 之间，不会溢出
+        if head >= 2 * SIZE {
+            head = head % SIZE + SIZE;
+        }
+        self.header.head = head as u32;
+        self.header.crc = self.header.checksum();
+    }
+}
+
+#[unsafe(link_section = ".noinit")]
+static mut RAMLOG: RamLog = RamLog::new();
+// 打印上一次日志时不能把它写回缓冲区
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// CRC-32（IEEE，反射多项式 0xedb88320），数据量很小，逐位计算即可
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// 检查并打印上一次的日志，然后清空缓冲区开始记录，必须在控制台初始化之后、
+/// 打印启动信息之前调用
+#[cfg(not(test))]
+pub fn init() {
+    let ramlog = unsafe { &mut RAMLOG };
+    if ramlog.header.is_valid() {
+        dump(ramlog, DUMP_LINES);
+    }
+    ramlog.reset();
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+// 打印最后 `lines` 行
+#[cfg(not(test))]
+fn dump(ramlog: &RamLog, lines: usize) {
+    let len = ramlog.len();
+    let start = ramlog.last_lines(lines);
+    println!(
+        "ramlog:  ---- previous boot, last {} bytes ----",
+        len - start
+    );
+    let mut chunk = [0; 64];
+    let mut i = start;
+    while i < len {
+        let n = (len - i).min(chunk.len());
+        for (j, byte) in chunk[..n].iter_mut().enumerate() {
+            *byte = ramlog.at(i + j);
+        }
+        console::write_bytes(&chunk[..n]);
+        i += n;
+    }
+    println!();
+    println!("ramlog:  ---- end of previous boot ----");
+}
+
+/// 追加一段输出，由控制台在临界区里调用
+pub fn write(bytes: &[u8]) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    unsafe { RAMLOG.append(bytes) };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{boxed::Box, format, vec::Vec};
+
+    use super::*;
+
+    fn contents(ramlog: &RamLog, start: usize) -> Vec<u8> {
+        (start..ramlog.len()).map(|i| ramlog.at(i)).collect()
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        // CRC-32/ISO-HDLC 的标准校验值
+        assert_eq!(!crc32_update(!0, b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn header_crc() {
+        let mut ramlog = Box::new(RamLog::new());
+        // 上电时的随机内容（这里是全 0）不算有效记录
+        assert!(!ramlog.header.is_valid());
+        ramlog.reset();
+        assert!(ramlog.header.is_valid());
+        ramlog.append(b"hello\n");
+        assert!(ramlog.header.is_valid());
+        // 写入位置被破坏
+        ramlog.header.head ^= 1;
+        assert!(!ramlog.header.is_valid());
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut ramlog = Box::new(RamLog::new());
+        ramlog.reset();
+        let data: Vec<u8> = (0..3 * SIZE + 100).map(|i| i as u8).collect();
+        for chunk in data.chunks(1000) {
+            ramlog.append(chunk);
+        }
+        let head = ramlog.header.head as usize;
+        assert!((SIZE..2 * SIZE).contains(&head));
+        assert_eq!(ramlog.len(), SIZE);
+        assert_eq!(contents(&ramlog, 0), data[data.len() - SIZE..]);
+        // 一次写入比缓冲区还长时只留最后一段
+        ramlog.append(&data);
+        assert_eq!(contents(&ramlog, 0), data[data.len() - SIZE..]);
+    }
+
+    #[test]
+    fn last_lines() {
+        let mut ramlog = Box::new(RamLog::new());
+        ramlog.reset();
+        for i in 0..40 {
+            ramlog.append(format!("line {}\n", i).as_bytes());
+        }
+        let start = ramlog.last_lines(DUMP_LINES);
+        let text = contents(&ramlog, start);
+        assert!(text.starts_with(b"line 8\n"));
+        assert!(text.ends_with(b"line 39\n"));
+        assert_eq!(text.iter().filter(|&&b| b == b'\n').count(), DUMP_LINES);
+
+        // 最后一行没写完（复位发生在写一半的时候）也算一行
+        ramlog.append(b"partial");
+        let text = contents(&ramlog, ramlog.last_lines(DUMP_LINES));
+        assert!(text.starts_with(b"line 9\n"));
+        assert!(text.ends_with(b"partial"));
+
+        // 不够那么多行时从头开始
+        ramlog.reset();
+        ramlog.append(b"a\nb\n");
+        assert_eq!(ramlog.last_lines(DUMP_LINES), 0);
+    }
+}
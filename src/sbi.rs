@@ -0,0 +1,166 @@
+//! SBI 调用封装，S 态运行时通过 `ecall` 请求 M 态固件（OpenSBI / RustSBI）
+//!
+//! 只封装了这里用到的扩展：Base、Timer、Debug Console、System Reset 以及旧版控制台。
+//! 扩展编号和错误码也被 M 态的 [`crate::handler`] 用来实现 SBI 服务端。
+
+use core::arch::asm;
+
+/// Base 扩展
+pub const EID_BASE: usize = 0x10;
+/// Timer 扩展 "TIME"
+pub const EID_TIME: usize = 0x5449_4D45;
+/// Debug Console 扩展 "DBCN"
+pub const EID_DBCN: usize = 0x4442_434E;
+/// IPI 扩展 "sPI"
+pub const EID_IPI: usize = 0x0073_5049;
+/// RFENCE 扩展 "RFNC"
+pub const EID_RFENCE: usize = 0x5246_4E43;
+/// HSM 扩展 "HSM"
+pub const EID_HSM: usize = 0x0048_534D;
+/// System Reset 扩展 "SRST"
+pub const EID_SRST: usize = 0x5352_5354;
+/// 旧版 `sbi_set_timer`
+pub const EID_LEGACY_SET_TIMER: usize = 0x00;
+/// 旧版 `sbi_console_putchar`
+pub const EID_LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+/// 旧版 `sbi_console_getchar`
+pub const EID_LEGACY_CONSOLE_GETCHAR: usize = 0x02;
+
+pub const BASE_GET_SPEC_VERSION: usize = 0;
+pub const BASE_GET_IMPL_ID: usize = 1;
+pub const BASE_GET_IMPL_VERSION: usize = 2;
+pub const BASE_PROBE_EXTENSION: usize = 3;
+pub const BASE_GET_MVENDORID: usize = 4;
+pub const BASE_GET_MARCHID: usize = 5;
+pub const BASE_GET_MIMPID: usize = 6;
+pub const TIME_SET_TIMER: usize = 0;
+pub const IPI_SEND_IPI: usize = 0;
+pub const RFENCE_REMOTE_FENCE_I: usize = 0;
+pub const RFENCE_REMOTE_SFENCE_VMA: usize = 1;
+pub const RFENCE_REMOTE_SFENCE_VMA_ASID: usize = 2;
+pub const HSM_HART_START: usize = 0;
+pub const HSM_HART_STOP: usize = 1;
+pub const HSM_HART_GET_STATUS: usize = 2;
+pub const HSM_HART_SUSPEND: usize = 3;
+pub const DBCN_CONSOLE_WRITE: usize = 0;
+pub const DBCN_CONSOLE_READ: usize = 1;
+pub const DBCN_CONSOLE_WRITE_BYTE: usize = 2;
+pub const SRST_SYSTEM_RESET: usize = 0;
+pub const RESET_TYPE_SHUTDOWN: usize = 0;
+pub const RESET_TYPE_COLD_REBOOT: usize = 1;
+pub const RESET_TYPE_WARM_REBOOT: usize = 2;
+pub const RESET_REASON_NONE: usize = 0;
+
+pub const SBI_SUCCESS: isize = 0;
+pub const SBI_ERR_FAILED: isize = -1;
+pub const SBI_ERR_NOT_SUPPORTED: isize = -2;
+pub const SBI_ERR_INVALID_PARAM: isize = -3;
+pub const SBI_ERR_INVALID_ADDRESS: isize = -5;
+pub const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
+
+/// SBI 调用返回值，`error` 为 0 表示成功
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SbiRet {
+    pub error: isize,
+    pub value: usize,
+}
+
+impl SbiRet {
+    pub const fn success(value: usize) -> Self {
+        Self {
+            error: SBI_SUCCESS,
+            value,
+        }
+    }
+
+    pub const fn error(error: isize) -> Self {
+        Self { error, value: 0 }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error == SBI_SUCCESS
+    }
+}
+
+#[inline(always)]
+fn sbi_call(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> SbiRet {
+    let (error, value);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") fid,
+            in("a7") eid,
+        )
+    };
+    SbiRet { error, value }
+}
+
+/// 查询固件是否实现了某个扩展
+pub fn probe_extension(eid: usize) -> bool {
+    let ret = sbi_call(EID_BASE, BASE_PROBE_EXTENSION, eid, 0, 0);
+    ret.is_ok() && ret.value != 0
+}
+
+/// 设置下一次 S 态定时器中断，同时清除当前挂起的 STIP
+pub fn set_timer(stime_value: u64) {
+    #[cfg(target_pointer_width = "64")]
+    sbi_call(EID_TIME, TIME_SET_TIMER, stime_value as usize, 0, 0);
+    #[cfg(target_pointer_width = "32")]
+    sbi_call(
+        EID_TIME,
+        TIME_SET_TIMER,
+        stime_value as usize,
+        (stime_value >> 32) as usize,
+        0,
+    );
+}
+
+/// 通过 DBCN 扩展输出一段字节，返回实际写出的字节数
+///
+/// S 态没有开分页，缓冲区的虚拟地址就是物理地址
+pub fn console_write(bytes: &[u8]) -> SbiRet {
+    sbi_call(
+        EID_DBCN,
+        DBCN_CONSOLE_WRITE,
+        bytes.len(),
+        bytes.as_ptr() as usize,
+        0,
+    )
+}
+
+/// 通过 DBCN 扩展输出一个字节
+pub fn console_write_byte(byte: u8) -> SbiRet {
+    sbi_call(EID_DBCN, DBCN_CONSOLE_WRITE_BYTE, byte as usize, 0, 0)
+}
+
+/// 旧版控制台输出，固件不支持 DBCN 时的回退
+pub fn legacy_console_putchar(byte: u8) {
+    sbi_call(EID_LEGACY_CONSOLE_PUTCHAR, 0, byte as usize, 0, 0);
+}
+
+/// 通过 DBCN 扩展读取输入，返回实际读到的字节数，没有输入时为 0
+pub fn console_read(buf: &mut [u8]) -> SbiRet {
+    sbi_call(
+        EID_DBCN,
+        DBCN_CONSOLE_READ,
+        buf.len(),
+        buf.as_mut_ptr() as usize,
+        0,
+    )
+}
+
+/// 旧版控制台输入，没有输入时返回 `None`
+pub fn legacy_console_getchar() -> Option<u8> {
+    // 旧版调用的返回值放在 a0
+    let ret = sbi_call(EID_LEGACY_CONSOLE_GETCHAR, 0, 0, 0, 0);
+    (ret.error >= 0).then_some(ret.error as u8)
+}
+
+/// 请求固件复位整个系统，成功时不会返回
+pub fn system_reset(reset_type: usize, reason: usize) -> SbiRet {
+    sbi_call(EID_SRST, SRST_SYSTEM_RESET, reset_type, reason, 0)
+}
@@ -0,0 +1,522 @@
+//! 控制台上的交互命令行
+//!
+//! [`run`] 任务轮询控制台输入，支持退格、Ctrl-U 清行、Ctrl-C 放弃当前行、
+//! 上下方向键翻历史和 Tab 补全命令名。内置 `help`、`gpio`（`qemu` 下没有）、`time`、
+//! `tasks`、`mem`、`mon`、`reset`，其它模块可以用 [`register`] 加上自己的命令。
+//!
+//! `payload` 模式下控制台输入归 S 态所有，不启动命令行。
+
+use core::cell::RefCell;
+
+use critical_section::{Mutex, with};
+use embassy_time::{Duration, Instant, Timer};
+
+#[cfg(not(feature = "qemu"))]
+use crate::gpio::{self, Bank, DriveStrength, Function, PadConfig, Pull, Slew};
+use crate::{
+    console,
+    monitor::{self, Width},
+    stack_guard, task_stats, time_driver, watchdog,
+};
+
+/// 一行最多的字节数
+const LINE_LEN: usize = 128;
+/// 一行最多的参数个数（包括命令名）
+const MAX_ARGS: usize = 8;
+/// 保存的历史命令条数
+const HISTORY_LEN: usize = 8;
+/// 通过 [`register`] 最多添加的命令数
+const MAX_COMMANDS: usize = 16;
+/// 没有输入时的轮询间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+const PROMPT: &str = "> ";
+
+/// 一条命令，`run` 的第一个参数是命令名本身
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    /// `help` 里显示的用法
+    pub help: &'static str,
+    pub run: fn(args: &[&str]) -> Result<(), &'static str>,
+}
+
+const BUILTINS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "help                       list commands",
+        run: cmd_help,
+    },
+    // QEMU virt 上没有 GPIO 控制器
+    #[cfg(not(feature = "qemu"))]
+    Command {
+        name: "gpio",
+        help: "gpio set|get|toggle <n> [0|1] / gpio pad|mux sys|aon <n> ...",
+        run: cmd_gpio,
+    },
+    Command {
+        name: "time",
+        help: "time                       uptime and raw mtime",
+        run: cmd_time,
+    },
+    Command {
+        name: "tasks",
+        help: "tasks [reset]              per-task poll statistics",
+        run: cmd_tasks,
+    },
+    Command {
+        name: "mem",
+        help: "mem peek <addr> [b|h|w|d] / mem poke <addr> <value> [b|h|w|d]",
+        run: cmd_mem,
+    },
+    Command {
+        name: "mon",
+        help: "mon r|w|d|csr ...          debug monitor commands",
+        run: cmd_mon,
+    },
+    Command {
+        name: "reset",
+        help: "reset                      reset the system",
+        run: cmd_reset,
+    },
+];
+
+static COMMANDS: Mutex<RefCell<[Option<Command>; MAX_COMMANDS]>> =
+    Mutex::new(RefCell::new([None; MAX_COMMANDS]));
+
+/// 添加一条命令，重名或者表满时返回 `false`
+pub fn register(command: Command) -> bool {
+    if find(command.name).is_some() {
+        return false;
+    }
+    with(|cs| {
+        let mut commands = COMMANDS.borrow_ref_mut(cs);
+        match commands.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(command);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+// 依次访问内置命令和注册的命令，回调里可以再打印或者访问命令表
+fn for_each_command(mut f: impl FnMut(&Command)) {
+    BUILTINS.iter().for_each(&mut f);
+    let commands = with(|cs| *COMMANDS.borrow_ref(cs));
+    commands.iter().flatten().for_each(f);
+}
+
+fn find(name: &str) -> Option<Command> {
+    let mut found = None;
+    for_each_command(|command| {
+        if found.is_none() && command.name == name {
+            found = Some(*command);
+        }
+    });
+    found
+}
+
+fn cmd_help(_args: &[&str]) -> Result<(), &'static str> {
+    for_each_command(|command| println!("  {}", command.help));
+    Ok(())
+}
+
+// 直接操作寄存器，不经过 `Gpio::take`，调试时可以改任务占用的管脚
+#[cfg(not(feature = "qemu"))]
+fn cmd_gpio(args: &[&str]) -> Result<(), &'static str> {
+    match args.get(1) {
+        Some(&"pad") => return cmd_gpio_pad(&args[2..]),
+        Some(&"mux") => return cmd_gpio_mux(&args[2..]),
+        _ => {}
+    }
+    let gpio = args
+        .get(2)
+        .and_then(|s| monitor::parse_number(s))
+        .filter(|&n| n < Bank::Sys.gpios() as u64)
+        .ok_or("invalid gpio number")? as u32;
+    match args[1..] {
+        ["set", _, value] => {
+            let high = match value {
+                "0" => false,
+                "1" => true,
+                _ => return Err("value must be 0 or 1"),
+            };
+            gpio::init_gpio_as_output(gpio::GPIO_BASE, gpio);
+            gpio::set_gpio_output(gpio::GPIO_BASE, gpio, high);
+        }
+        ["get", _] => println!(
+            "gpio{} = {}",
+            gpio,
+            gpio::read_gpio(gpio::GPIO_BASE, gpio) as u8
+        ),
+        ["toggle", _] => gpio::toggle_gpio(gpio::GPIO_BASE, gpio),
+        _ => return Err("usage: gpio set|get|toggle <n> [0|1]"),
+    }
+    Ok(())
+}
+
+// `sys|aon <n>` 给出的管脚
+#[cfg(not(feature = "qemu"))]
+fn parse_pin(args: &[&str]) -> Result<(Bank, u32), &'static str> {
+    let bank = match args.first() {
+        Some(&"sys") => Bank::Sys,
+        Some(&"aon") => Bank::Aon,
+        _ => return Err("bank must be sys or aon"),
+    };
+    let gpio = args
+        .get(1)
+        .and_then(|s| monitor::parse_number(s))
+        .filter(|&n| n < bank.gpios() as u64)
+        .ok_or("invalid gpio number")? as u32;
+    Ok((bank, gpio))
+}
+
+// gpio pad sys|aon <n> [选项,...]：从默认配置（2mA、无上下拉、慢速、输入使能）改起
+#[cfg(not(feature = "qemu"))]
+fn cmd_gpio_pad(args: &[&str]) -> Result<(), &'static str> {
+    const USAGE: &str = "usage: gpio pad sys|aon <n> [up|down,2ma|4ma|8ma|12ma,fast,smt,noie]";
+    if args.len() > 3 {
+        return Err(USAGE);
+    }
+    let (bank, gpio) = parse_pin(args)?;
+    let mut config = PadConfig::new();
+    let options = args.get(2).copied().unwrap_or("");
+    for option in options.split(',').filter(|s| !s.is_empty()) {
+        config = match option {
+            "up" => config.pull(Pull::Up),
+            "down" => config.pull(Pull::Down),
+            "2ma" => config.drive(DriveStrength::Ma2),
+            "4ma" => config.drive(DriveStrength::Ma4),
+            "8ma" => config.drive(DriveStrength::Ma8),
+            "12ma" => config.drive(DriveStrength::Ma12),
+            "fast" => config.slew(Slew::Fast),
+            "smt" => config.schmitt(true),
+            "noie" => config.input_enable(false),
+            _ => return Err(USAGE),
+        };
+    }
+    gpio::set_pad_config(bank, gpio, config);
+    Ok(())
+}
+
+// gpio mux sys|aon <n> out <dout> / in <din> / <dout> <doen> <din>，
+// 信号编号见 Linux 的 dt-bindings/pinctrl/starfive,jh7110-pinctrl.h
+#[cfg(not(feature = "qemu"))]
+fn cmd_gpio_mux(args: &[&str]) -> Result<(), &'static str> {
+    let (bank, gpio) = parse_pin(args)?;
+    let signal = |index: usize| {
+        args.get(index)
+            .and_then(|s| monitor::parse_number(s))
+            .filter(|&n| n <= u8::MAX as u64)
+            .map(|n| n as u32)
+            .ok_or("invalid signal number")
+    };
+    let function = match args[2..] {
+        ["out", _] => Function::output(signal(3)?),
+        ["in", _] => Function::input(signal(3)?),
+        [_, _, _] => Function::bidirectional(signal(2)?, signal(3)?, signal(4)?),
+        _ => return Err("usage: gpio mux sys|aon <n> out <dout> | in <din> | <dout> <doen> <din>"),
+    };
+    gpio::set_function(bank, gpio, function);
+    Ok(())
+}
+
+fn cmd_time(_args: &[&str]) -> Result<(), &'static str> {
+    let micros = Instant::now().as_micros();
+    println!(
+        "uptime {}.{:06} s, mtime {:#x} @ {} Hz",
+        micros / 1_000_000,
+        micros % 1_000_000,
+        time_driver::mtime(),
+        time_driver::mtime_hz()
+    );
+    Ok(())
+}
+
+fn cmd_tasks(args: &[&str]) -> Result<(), &'static str> {
+    match args.get(1).copied() {
+        None => task_stats::dump(),
+        #[cfg(feature = "task-stats")]
+        Some("reset") => {
+            task_stats::reset();
+            return Ok(());
+        }
+        _ => return Err("usage: tasks [reset]"),
+    }
+    println!(
+        "stack high-water {:#x} bytes, canary {}",
+        stack_guard::high_water_mark(),
+        if stack_guard::canary_intact() {
+            "intact"
+        } else {
+            "damaged"
+        }
+    );
+    println!("console dropped {} bytes", console::dropped());
+    Ok(())
+}
+
+fn cmd_mem(args: &[&str]) -> Result<(), &'static str> {
+    let addr = args
+        .get(2)
+        .and_then(|s| monitor::parse_usize(s))
+        .ok_or("invalid address")?;
+    let width = |i: usize| Width::parse(args.get(i).copied()).ok_or("width must be b, h, w or d");
+    match args.get(1).copied() {
+        Some("peek") => {
+            let width = width(3)?;
+            let value = monitor::peek(addr, width)?;
+            println!("{:#x}: {:#0w$x}", addr, value, w = width.bytes() * 2 + 2);
+        }
+        Some("poke") => {
+            let value = args
+                .get(3)
+                .and_then(|s| monitor::parse_number(s))
+                .ok_or("invalid value")?;
+            monitor::poke(addr, value, width(4)?)?;
+        }
+        _ => return Err("usage: mem peek|poke <addr> ..."),
+    }
+    Ok(())
+}
+
+fn cmd_mon(args: &[&str]) -> Result<(), &'static str> {
+    monitor::command(&args[1..])
+}
+
+fn cmd_reset(_args: &[&str]) -> Result<(), &'static str> {
+    println!("resetting...");
+    watchdog::system_reset()
+}
+
+fn execute(line: &str) {
+    let mut args = [""; MAX_ARGS];
+    let mut argc = 0;
+    for word in line.split_ascii_whitespace() {
+        if argc == MAX_ARGS {
+            println!("error: too many arguments");
+            return;
+        }
+        args[argc] = word;
+        argc += 1;
+    }
+    if argc == 0 {
+        return;
+    }
+    match find(args[0]) {
+        Some(command) => {
+            if let Err(error) = (command.run)(&args[..argc]) {
+                println!("error: {}", error);
+            }
+        }
+        None => println!("unknown command `{}`, try `help`", args[0]),
+    }
+}
+
+// 方向键是 `ESC [ A` 这样的转义序列
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Esc,
+    Csi,
+}
+
+struct Editor {
+    line: [u8; LINE_LEN],
+    len: usize,
+    escape: Escape,
+    history: [([u8; LINE_LEN], usize); HISTORY_LEN],
+    // 已保存的历史条数和下一条写入的位置
+    history_count: usize,
+    history_next: usize,
+    // 正在浏览的历史，0 表示最近一条
+    browsing: Option<usize>,
+}
+
+impl Editor {
+    const fn new() -> Self {
+        Self {
+            line: [0; LINE_LEN],
+            len: 0,
+            escape: Escape::None,
+            history: [([0; LINE_LEN], 0); HISTORY_LEN],
+            history_count: 0,
+            history_next: 0,
+            browsing: None,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // 只接受可打印 ASCII，一定是合法 UTF-8
+        core::str::from_utf8(&self.line[..self.len]).unwrap_or("")
+    }
+
+    // 用新内容替换当前行并重画
+    fn replace(&mut self, content: &[u8]) {
+        for _ in 0..self.len {
+            print!("\x08 \x08");
+        }
+        self.len = content.len();
+        self.line[..self.len].copy_from_slice(content);
+        print!("{}", self.as_str());
+    }
+
+    fn push_history(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        let last = (self.history_next + HISTORY_LEN - 1) % HISTORY_LEN;
+        let (line, len) = &self.history[last];
+        if self.history_count > 0 && line[..*len] == self.line[..self.len] {
+            return;
+        }
+        self.history[self.history_next] = (self.line, self.len);
+        self.history_next = (self.history_next + 1) % HISTORY_LEN;
+        self.history_count = (self.history_count + 1).min(HISTORY_LEN);
+    }
+
+    fn browse(&mut self, older: bool) {
+        let next = match (self.browsing, older) {
+            (None, true) if self.history_count > 0 => Some(0),
+            (Some(i), true) if i + 1 < self.history_count => Some(i + 1),
+            (Some(0), false) => None,
+            (Some(i), false) => Some(i - 1),
+            (current, _) => current,
+        };
+        if next == self.browsing {
+            return;
+        }
+        self.browsing = next;
+        match next {
+            Some(i) => {
+                let (line, len) =
+                    self.history[(self.history_next + HISTORY_LEN - 1 - i) % HISTORY_LEN];
+                self.replace(&line[..len]);
+            }
+            None => self.replace(&[]),
+        }
+    }
+
+    // 只补全命令名：唯一匹配时补全并加空格，多个匹配时列出并补到公共前缀
+    fn complete(&mut self) {
+        if self.line[..self.len].contains(&b' ') {
+            return;
+        }
+        let prefix = self.as_str();
+        let mut matches = 0;
+        let mut common: Option<&'static str> = None;
+        let mut common_len = 0;
+        for_each_command(|command| {
+            if !command.name.starts_with(prefix) {
+                return;
+            }
+            matches += 1;
+            match common {
+                None => {
+                    common = Some(command.name);
+                    common_len = command.name.len();
+                }
+                Some(first) => {
+                    common_len = first
+                        .bytes()
+                        .zip(command.name.bytes())
+                        .take(common_len)
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                }
+            }
+        });
+        let Some(first) = common else {
+            return;
+        };
+        if matches > 1 {
+            println!();
+            for_each_command(|command| {
+                if command.name.starts_with(prefix) {
+                    print!("{}  ", command.name);
+                }
+            });
+            println!();
+            print!("{}{}", PROMPT, self.as_str());
+        }
+        let mut completed = [0; LINE_LEN];
+        let mut len = common_len.min(LINE_LEN);
+        completed[..len].copy_from_slice(&first.as_bytes()[..len]);
+        if matches == 1 && len < LINE_LEN {
+            completed[len] = b' ';
+            len += 1;
+        }
+        self.replace(&completed[..len]);
+    }
+
+    // 处理一个输入字节，读到完整的一行时执行
+    fn feed(&mut self, byte: u8) {
+        match (self.escape, byte) {
+            (Escape::Esc, b'[') => {
+                self.escape = Escape::Csi;
+                return;
+            }
+            (Escape::Csi, b'A') => self.browse(true),
+            (Escape::Csi, b'B') => self.browse(false),
+            // 其它转义序列直接忽略
+            (Escape::Csi, 0x40..=0x7e) | (Escape::Esc, _) => {}
+            (Escape::Csi, _) => return,
+            (Escape::None, 0x1b) => {
+                self.escape = Escape::Esc;
+                return;
+            }
+            (Escape::None, b'\r' | b'\n') => {
+                println!();
+                self.push_history();
+                let line = self.line;
+                let len = self.len;
+                self.len = 0;
+                self.browsing = None;
+                execute(core::str::from_utf8(&line[..len]).unwrap_or(""));
+                print!("{}", PROMPT);
+            }
+            (Escape::None, 0x08 | 0x7f) => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    print!("\x08 \x08");
+                }
+            }
+            // Ctrl-C
+            (Escape::None, 0x03) => {
+                println!("^C");
+                self.len = 0;
+                self.browsing = None;
+                print!("{}", PROMPT);
+            }
+            // Ctrl-U
+            (Escape::None, 0x15) => self.replace(&[]),
+            (Escape::None, b'\t') => self.complete(),
+            (Escape::None, 0x20..=0x7e) => {
+                if self.len < LINE_LEN {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                    print!("{}", byte as char);
+                }
+            }
+            _ => {}
+        }
+        self.escape = Escape::None;
+    }
+}
+
+/// 命令行任务
+#[embassy_executor::task]
+pub async fn run() {
+    let mut editor = Editor::new();
+    let mut buf = [0; 16];
+    print!("{}", PROMPT);
+    loop {
+        let count = console::read(&mut buf);
+        if count == 0 {
+            Timer::after(POLL_INTERVAL).await;
+            continue;
+        }
+        buf[..count].iter().for_each(|&byte| editor.feed(byte));
+    }
+}
@@ -0,0 +1,625 @@
+//! ARM PL022 SPI 主机驱动，实现 `embedded_hal_async::spi::SpiBus`
+//!
+//! 固定 8 位数据帧。发送时保证 FIFO 里在途的字节不超过 FIFO 深度，这样接收 FIFO
+//! 不会溢出；等待时打开接收半满（RXIM）和接收超时（RTIM）中断，中断里屏蔽后唤醒任务。
+//! [`SpiDevice`] 在总线外面加上用 GPIO 控制的片选，多个设备通过同一把锁共享总线。
+//! 寄存器访问经过 [`Registers`]，和 I2C 驱动一样可以在主机上用软件模型测试。
+
+use core::{future::poll_fn, task::Poll};
+
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, waitqueue::AtomicWaker,
+};
+use embassy_time::Timer;
+use embedded_hal::{
+    digital::OutputPin,
+    spi::{ErrorKind, ErrorType, Mode, Operation, Phase, Polarity},
+};
+
+#[cfg(not(test))]
+use crate::{
+    clock::{self, Crg, ResetTimeout, SYSCLK_SPI0_APB, SYSRST_SPI0_APB},
+    plic,
+};
+
+/// SPI0~6 的基地址
+const BASES: [usize; INSTANCES] = [
+    0x1006_0000,
+    0x1007_0000,
+    0x1008_0000,
+    0x1207_0000,
+    0x1208_0000,
+    0x1209_0000,
+    0x120a_0000,
+];
+/// SPI0~6 在 PLIC 上的中断号
+const IRQS: [usize; INSTANCES] = [38, 39, 40, 52, 53, 54, 55];
+const INSTANCES: usize = 7;
+
+// 读不到时钟频率时按 APB0 的默认值计算
+const DEFAULT_CLK_HZ: u64 = 49_500_000;
+
+const SSPCR0: usize = 0x00;
+const SSPCR1: usize = 0x04;
+const SSPDR: usize = 0x08;
+const SSPSR: usize = 0x0c;
+const SSPCPSR: usize = 0x10;
+const SSPIMSC: usize = 0x14;
+const SSPRIS: usize = 0x18;
+const SSPMIS: usize = 0x1c;
+const SSPICR: usize = 0x20;
+
+const CR0_DSS_8BIT: u32 = 7;
+const CR0_SPO: u32 = 1 << 6;
+const CR0_SPH: u32 = 1 << 7;
+const CR0_SCR_SHIFT: u32 = 8;
+const CR1_SSE: u32 = 1 << 1;
+
+const SR_TNF: u32 = 1 << 1;
+const SR_RNE: u32 = 1 << 2;
+const SR_BSY: u32 = 1 << 4;
+
+const INT_ROR: u32 = 1 << 0;
+const INT_RT: u32 = 1 << 1;
+const INT_RX: u32 = 1 << 2;
+
+const FIFO_DEPTH: usize = 8;
+
+static WAKERS: [AtomicWaker; INSTANCES] = [const { AtomicWaker::new() }; INSTANCES];
+
+const HANDLERS: [fn(); INSTANCES] = [
+    on_interrupt::<0>,
+    on_interrupt::<1>,
+    on_interrupt::<2>,
+    on_interrupt::<3>,
+    on_interrupt::<4>,
+    on_interrupt::<5>,
+    on_interrupt::<6>,
+];
+
+fn on_interrupt<const N: usize>() {
+    let regs = Mmio(BASES[N]);
+    if regs.read(SSPMIS) != 0 {
+        regs.write(SSPIMSC, 0);
+        WAKERS[N].wake();
+    }
+}
+
+/// 控制器寄存器的读写
+pub trait Registers {
+    fn read(&self, offset: usize) -> u32;
+    fn write(&self, offset: usize, value: u32);
+}
+
+/// 直接访问 MMIO 寄存器
+pub struct Mmio(usize);
+
+impl Registers for Mmio {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.0 + offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ((self.0 + offset) as *mut u32).write_volatile(value) }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    pub mode: Mode,
+    /// 期望的 SCK 频率，实际频率不超过该值，但不低于 SSPCLK / (254 * 256)
+    pub frequency: u32,
+}
+
+impl Config {
+    pub const fn new(mode: Mode, frequency: u32) -> Self {
+        Self { mode, frequency }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// 接收 FIFO 溢出
+    Overrun,
+}
+
+impl embedded_hal::spi::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Overrun => ErrorKind::Overrun,
+        }
+    }
+}
+
+// 一次传输的收发缓冲区
+enum Buffers<'a> {
+    Split(&'a mut [u8], &'a [u8]),
+    InPlace(&'a mut [u8]),
+}
+
+impl Buffers<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Buffers::Split(read, write) => read.len().max(write.len()),
+            Buffers::InPlace(buf) => buf.len(),
+        }
+    }
+
+    // 写缓冲区比读缓冲区短时补 0
+    fn tx(&self, index: usize) -> u8 {
+        match self {
+            Buffers::Split(_, write) => write.get(index).copied().unwrap_or(0),
+            Buffers::InPlace(buf) => buf[index],
+        }
+    }
+
+    // 读缓冲区比写缓冲区短时丢弃多余的数据
+    fn rx(&mut self, index: usize, byte: u8) {
+        match self {
+            Buffers::Split(read, _) => {
+                if let Some(slot) = read.get_mut(index) {
+                    *slot = byte;
+                }
+            }
+            Buffers::InPlace(buf) => buf[index] = byte,
+        }
+    }
+}
+
+// SCK = SSPCLK / (CPSDVSR * (1 + SCR))，CPSDVSR 为 2~254 的偶数，SCR 为 0~255
+fn divider(clk_hz: u64, frequency: u32) -> (u32, u32) {
+    let frequency = frequency.max(1) as u64;
+    for cpsdvsr in (2..=254u64).step_by(2) {
+        let scr = clk_hz.div_ceil(cpsdvsr * frequency).max(1) - 1;
+        if scr <= 255 {
+            return (cpsdvsr as u32, scr as u32);
+        }
+    }
+    (254, 255)
+}
+
+pub struct Spi<R: Registers = Mmio> {
+    regs: R,
+    index: usize,
+    clk_hz: u64,
+}
+
+#[cfg(not(test))]
+impl Spi<Mmio> {
+    /// 打开 SPI`index` 的时钟、释放复位并挂上中断，管脚需要事先用
+    /// [`crate::gpio::set_function`] 接好
+    pub fn new(index: usize, config: Config) -> Result<Self, ResetTimeout> {
+        assert!(index < INSTANCES, "invalid spi {}", index);
+        let clk = SYSCLK_SPI0_APB + index as u32;
+        clock::bring_up(Crg::Sys, &[clk], &[SYSRST_SPI0_APB + index as u32])?;
+        let clk_hz = clock::sys_rate(clk)
+            .filter(|&hz| hz != 0)
+            .unwrap_or(DEFAULT_CLK_HZ);
+        let spi = Self::with_registers(Mmio(BASES[index]), index, clk_hz, config);
+        plic::register(IRQS[index], HANDLERS[index]);
+        Ok(spi)
+    }
+}
+
+impl<R: Registers> Spi<R> {
+    /// 用给定的寄存器访问方式初始化控制器，`index` 决定使用哪一个唤醒槽
+    pub fn with_registers(regs: R, index: usize, clk_hz: u64, config: Config) -> Self {
+        let mut spi = Self {
+            regs,
+            index,
+            clk_hz,
+        };
+        spi.set_config(config);
+        spi
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        self.regs.read(offset)
+    }
+
+    fn write_reg(&self, offset: usize, value: u32) {
+        self.regs.write(offset, value)
+    }
+
+    /// 修改模式和频率，会短暂关闭控制器
+    pub fn set_config(&mut self, config: Config) {
+        let (cpsdvsr, scr) = divider(self.clk_hz, config.frequency);
+        let mut cr0 = CR0_DSS_8BIT | (scr << CR0_SCR_SHIFT);
+        if config.mode.polarity == Polarity::IdleHigh {
+            cr0 |= CR0_SPO;
+        }
+        if config.mode.phase == Phase::CaptureOnSecondTransition {
+            cr0 |= CR0_SPH;
+        }
+        self.write_reg(SSPCR1, 0);
+        self.write_reg(SSPCR0, cr0);
+        self.write_reg(SSPCPSR, cpsdvsr);
+        self.write_reg(SSPIMSC, 0);
+        self.write_reg(SSPICR, INT_ROR | INT_RT);
+        // 主机模式，打开控制器
+        self.write_reg(SSPCR1, CR1_SSE);
+        // 清掉上次残留的接收数据
+        while self.read_reg(SSPSR) & SR_RNE != 0 {
+            self.read_reg(SSPDR);
+        }
+    }
+
+    /// 当前配置下实际的 SCK 频率
+    pub fn frequency(&self) -> u32 {
+        let cpsdvsr = self.read_reg(SSPCPSR) & 0xff;
+        let scr = (self.read_reg(SSPCR0) >> CR0_SCR_SHIFT) & 0xff;
+        (self.clk_hz / (cpsdvsr.max(2) as u64 * (scr as u64 + 1))) as u32
+    }
+
+    // 上一次传输的 future 中途被丢弃时 FIFO 里可能还有数据，等它们收回后丢掉
+    fn discard_stale(&mut self) {
+        while self.read_reg(SSPSR) & (SR_BSY | SR_RNE) != 0 {
+            self.read_reg(SSPDR);
+        }
+    }
+
+    async fn run(&mut self, mut buffers: Buffers<'_>) -> Result<(), Error> {
+        self.discard_stale();
+        let len = buffers.len();
+        let mut tx = 0;
+        let mut rx = 0;
+        poll_fn(|cx| {
+            WAKERS[self.index].register(cx.waker());
+            if self.read_reg(SSPRIS) & INT_ROR != 0 {
+                self.write_reg(SSPICR, INT_ROR);
+                return Poll::Ready(Err(Error::Overrun));
+            }
+            loop {
+                let mut progress = false;
+                while rx < tx && self.read_reg(SSPSR) & SR_RNE != 0 {
+                    buffers.rx(rx, self.read_reg(SSPDR) as u8);
+                    rx += 1;
+                    progress = true;
+                }
+                while tx < len && tx - rx < FIFO_DEPTH && self.read_reg(SSPSR) & SR_TNF != 0 {
+                    self.write_reg(SSPDR, buffers.tx(tx) as u32);
+                    tx += 1;
+                    progress = true;
+                }
+                if rx == len {
+                    return Poll::Ready(Ok(()));
+                }
+                if !progress {
+                    break;
+                }
+            }
+            self.write_reg(SSPICR, INT_RT);
+            self.write_reg(SSPIMSC, INT_ROR | INT_RT | INT_RX);
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+impl<R: Registers> ErrorType for Spi<R> {
+    type Error = Error;
+}
+
+impl<R: Registers> embedded_hal_async::spi::SpiBus for Spi<R> {
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.run(Buffers::Split(words, &[])).await
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.run(Buffers::Split(&mut [], words)).await
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        self.run(Buffers::Split(read, write)).await
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.run(Buffers::InPlace(words)).await
+    }
+
+    // 每次传输都等所有数据收回才返回，这里只需要等移位寄存器空闲
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        while self.read_reg(SSPSR) & SR_BSY != 0 {
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+}
+
+/// 共享总线上的一个设备，片选为低电平有效的 GPIO，例如 `gpio::Pin<N, Output>`
+pub struct SpiDevice<'a, CS: OutputPin, R: Registers = Mmio> {
+    bus: &'a Mutex<CriticalSectionRawMutex, Spi<R>>,
+    cs: CS,
+}
+
+impl<'a, CS: OutputPin, R: Registers> SpiDevice<'a, CS, R> {
+    pub fn new(bus: &'a Mutex<CriticalSectionRawMutex, Spi<R>>, mut cs: CS) -> Self {
+        let _ = cs.set_high();
+        Self { bus, cs }
+    }
+}
+
+// 片选在离开作用域时释放：出错返回、或者 transaction 的 future 中途被丢弃都一样。
+// 要在总线锁之后创建，先于总线锁释放
+struct ChipSelect<'a, CS: OutputPin>(&'a mut CS);
+
+impl<'a, CS: OutputPin> ChipSelect<'a, CS> {
+    fn select(cs: &'a mut CS) -> Self {
+        let _ = cs.set_low();
+        Self(cs)
+    }
+}
+
+impl<CS: OutputPin> Drop for ChipSelect<'_, CS> {
+    fn drop(&mut self) {
+        let _ = self.0.set_high();
+    }
+}
+
+impl<CS: OutputPin, R: Registers> ErrorType for SpiDevice<'_, CS, R> {
+    type Error = Error;
+}
+
+impl<CS: OutputPin, R: Registers> embedded_hal_async::spi::SpiDevice for SpiDevice<'_, CS, R> {
+    async fn transaction(
+        &mut self,
+        operations: &mut [Operation<'_, u8>],
+    ) -> Result<(), Self::Error> {
+        use embedded_hal_async::spi::SpiBus;
+
+        let mut bus = self.bus.lock().await;
+        let _cs = ChipSelect::select(&mut self.cs);
+        for operation in operations {
+            match operation {
+                Operation::Read(words) => bus.read(words).await?,
+                Operation::Write(words) => bus.write(words).await?,
+                Operation::Transfer(read, write) => bus.transfer(read, write).await?,
+                Operation::TransferInPlace(words) => bus.transfer_in_place(words).await?,
+                Operation::DelayNs(ns) => Timer::after_nanos(*ns as u64).await,
+            }
+        }
+        bus.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        cell::{Cell, RefCell},
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+    use std::{collections::VecDeque, vec::Vec};
+
+    use embedded_hal_async::spi::{SpiBus as _, SpiDevice as _};
+
+    use super::*;
+
+    const CLK_HZ: u64 = 48_000_000;
+
+    // 寄存器级的控制器模型：写进 SSPDR 的字节立刻移出，从机回应该字节加 1
+    #[derive(Default)]
+    struct State {
+        cr0: u32,
+        cr1: u32,
+        cpsr: u32,
+        imsc: u32,
+        ris: u32,
+        // 所有发出的字节
+        sent: Vec<u8>,
+        rx: VecDeque<u8>,
+        // 接收 FIFO 里同时存在过的最多字节数
+        max_rx: usize,
+        // 从机不给时钟，字节留在移位寄存器里
+        stall: bool,
+        pending: VecDeque<u8>,
+    }
+
+    impl State {
+        fn receive(&mut self, byte: u8) {
+            if self.rx.len() == FIFO_DEPTH {
+                self.ris |= INT_ROR;
+                return;
+            }
+            self.rx.push_back(byte.wrapping_add(1));
+            self.max_rx = self.max_rx.max(self.rx.len());
+        }
+
+        // 恢复时钟，把卡住的字节移完
+        fn resume(&mut self) {
+            self.stall = false;
+            while let Some(byte) = self.pending.pop_front() {
+                self.receive(byte);
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Model(RefCell<State>);
+
+    impl Registers for &Model {
+        fn read(&self, offset: usize) -> u32 {
+            let mut state = self.0.borrow_mut();
+            match offset {
+                SSPCR0 => state.cr0,
+                SSPCR1 => state.cr1,
+                SSPCPSR => state.cpsr,
+                SSPIMSC => state.imsc,
+                SSPRIS => state.ris,
+                SSPMIS => state.ris & state.imsc,
+                SSPSR => {
+                    let mut sr = 0;
+                    if state.pending.len() < FIFO_DEPTH {
+                        sr |= SR_TNF;
+                    }
+                    if !state.rx.is_empty() {
+                        sr |= SR_RNE;
+                    }
+                    if !state.pending.is_empty() {
+                        sr |= SR_BSY;
+                    }
+                    sr
+                }
+                SSPDR => state.rx.pop_front().unwrap_or(0) as u32,
+                _ => 0,
+            }
+        }
+
+        fn write(&self, offset: usize, value: u32) {
+            let mut state = self.0.borrow_mut();
+            match offset {
+                SSPCR0 => state.cr0 = value,
+                SSPCR1 => state.cr1 = value,
+                SSPCPSR => state.cpsr = value,
+                SSPIMSC => state.imsc = value,
+                SSPICR => state.ris &= !value,
+                SSPDR => {
+                    assert_ne!(state.cr1 & CR1_SSE, 0, "data written while disabled");
+                    let byte = value as u8;
+                    state.sent.push(byte);
+                    if state.stall {
+                        state.pending.push_back(byte);
+                    } else {
+                        state.receive(byte);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // 片选管脚，记录当前电平
+    struct Cs<'a>(&'a Cell<bool>);
+
+    impl embedded_hal::digital::ErrorType for Cs<'_> {
+        type Error = core::convert::Infallible;
+    }
+
+    impl OutputPin for Cs<'_> {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.0.set(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.0.set(true);
+            Ok(())
+        }
+    }
+
+    fn spi(model: &Model) -> Spi<&Model> {
+        Spi::with_registers(
+            model,
+            0,
+            CLK_HZ,
+            Config::new(embedded_hal::spi::MODE_0, 1_000_000),
+        )
+    }
+
+    // 没有中断，反复 poll 直到完成
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        for _ in 0..1000 {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+        panic!("transfer did not finish");
+    }
+
+    #[test]
+    fn divider_never_exceeds_frequency() {
+        // 最低能分到 CLK_HZ / (254 * 256)，更低的见 divider_limits
+        for frequency in [1_000, 100_000, 1_000_000, 3_000_000, 7_000_000, 24_000_000] {
+            let (cpsdvsr, scr) = divider(CLK_HZ, frequency);
+            assert!((2..=254).contains(&cpsdvsr) && cpsdvsr % 2 == 0);
+            assert!(scr <= 255);
+            let actual = CLK_HZ / (cpsdvsr as u64 * (scr as u64 + 1));
+            assert!(actual <= frequency as u64, "{} > {}", actual, frequency);
+        }
+        // 能整除时正好等于期望值
+        assert_eq!(divider(CLK_HZ, 1_000_000), (2, 23));
+    }
+
+    #[test]
+    fn divider_limits() {
+        // 超过 SSPCLK / 2 时用最小分频
+        assert_eq!(divider(CLK_HZ, u32::MAX), (2, 0));
+        // 太低时用最大分频，实际频率会高于期望值
+        assert_eq!(divider(CLK_HZ, 1), (254, 255));
+        assert_eq!(divider(CLK_HZ, 0), (254, 255));
+    }
+
+    #[test]
+    fn set_config_programs_divider() {
+        let model = Model::default();
+        let mut spi = spi(&model);
+        assert_eq!(spi.frequency(), 1_000_000);
+        spi.set_config(Config::new(embedded_hal::spi::MODE_3, 400_000));
+        let state = model.0.borrow();
+        assert_eq!(state.cr0 & (CR0_SPO | CR0_SPH), CR0_SPO | CR0_SPH);
+        assert_ne!(state.cr1 & CR1_SSE, 0);
+        drop(state);
+        assert!(spi.frequency() <= 400_000);
+    }
+
+    #[test]
+    fn in_flight_bytes_fit_rx_fifo() {
+        let model = Model::default();
+        let mut spi = spi(&model);
+        let write: Vec<u8> = (0..64).collect();
+        let mut read = [0; 64];
+        assert_eq!(block_on(spi.transfer(&mut read, &write)), Ok(()));
+        let state = model.0.borrow();
+        assert_eq!(state.sent, write);
+        assert!(state.max_rx <= FIFO_DEPTH, "rx fifo held {}", state.max_rx);
+        assert_eq!(state.ris & INT_ROR, 0);
+        assert!(read.iter().zip(&write).all(|(&r, &w)| r == w + 1));
+    }
+
+    #[test]
+    fn overrun() {
+        let model = Model::default();
+        let mut spi = spi(&model);
+        model.0.borrow_mut().ris |= INT_ROR;
+        assert_eq!(block_on(spi.write(&[1])), Err(Error::Overrun));
+        assert_eq!(model.0.borrow().ris & INT_ROR, 0);
+    }
+
+    #[test]
+    fn chip_select_released_on_error() {
+        let model = Model::default();
+        let bus = Mutex::new(spi(&model));
+        let level = Cell::new(false);
+        let mut device = SpiDevice::new(&bus, Cs(&level));
+        model.0.borrow_mut().ris |= INT_ROR;
+        assert_eq!(block_on(device.write(&[1, 2])), Err(Error::Overrun));
+        assert!(level.get());
+    }
+
+    #[test]
+    fn chip_select_released_on_drop() {
+        let model = Model::default();
+        let bus = Mutex::new(spi(&model));
+        let level = Cell::new(false);
+        let mut device = SpiDevice::new(&bus, Cs(&level));
+        model.0.borrow_mut().stall = true;
+        {
+            let mut future = pin!(device.write(&[1, 2, 3]));
+            let mut cx = Context::from_waker(Waker::noop());
+            assert!(future.as_mut().poll(&mut cx).is_pending());
+            assert!(!level.get());
+        }
+        assert!(level.get());
+
+        // 被丢弃的传输剩下的数据不会混进下一次传输
+        model.0.borrow_mut().resume();
+        let mut read = [0; 2];
+        assert_eq!(block_on(device.transfer(&mut read, &[10, 20])), Ok(()));
+        assert_eq!(read, [11, 21]);
+        assert!(level.get());
+    }
+}
@@ -0,0 +1,116 @@
+//! 栈溢出检测
+//!
+//! 两层保护：
+//! * 栈底下方由链接脚本留出 4 KiB 的 `stack_guard` 区，有 PMP 时由 [`crate::pmp`]
+//!   配置成锁定的禁止访问区，越界访问会直接触发 access fault；
+//! * 栈底涂上金丝雀（canary）图案，由 [`monitor`] 任务周期检查，panic 时也会检查一遍，
+//!   同时根据图案被覆盖的位置算出栈的最高水位。
+
+use core::{
+    arch::asm,
+    mem::size_of,
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use embassy_time::{Duration, Timer};
+
+use crate::pmp;
+
+/// 金丝雀图案
+const CANARY: usize = 0x5a5a_5a5a_5a5a_5a5a_u64 as usize;
+/// 栈底必须保持完整的金丝雀字数
+const CANARY_WORDS: usize = 16;
+/// 涂图案时在当前 sp 下方留出的余量，避免踩到正在使用的栈帧
+const PAINT_MARGIN: usize = 256;
+/// 周期检查的间隔
+const CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+static STACK_BOTTOM: AtomicUsize = AtomicUsize::new(0);
+static STACK_TOP: AtomicUsize = AtomicUsize::new(0);
+
+/// 记录栈范围并涂金丝雀
+///
+/// 必须在切换到 `stack` 之后调用
+pub fn init(stack: Range<usize>) {
+    STACK_BOTTOM.store(stack.start, Ordering::Relaxed);
+    STACK_TOP.store(stack.end, Ordering::Relaxed);
+    paint(stack);
+}
+
+#[inline(always)]
+fn current_sp() -> usize {
+    let sp: usize;
+    unsafe { asm!("mv {}, sp", out(reg) sp) };
+    sp
+}
+
+fn paint(stack: Range<usize>) {
+    // 当前就跑在这块栈上，只涂到 sp 下方留出余量的位置
+    let end = current_sp()
+        .saturating_sub(PAINT_MARGIN)
+        .clamp(stack.start, stack.end)
+        & !(size_of::<usize>() - 1);
+    let mut addr = stack.start;
+    while addr < end {
+        unsafe { (addr as *mut usize).write_volatile(CANARY) };
+        addr += size_of::<usize>();
+    }
+}
+
+/// 栈底的金丝雀是否完好
+pub fn canary_intact() -> bool {
+    let bottom = STACK_BOTTOM.load(Ordering::Relaxed);
+    if bottom == 0 {
+        return true;
+    }
+    (0..CANARY_WORDS).all(|i| {
+        let addr = bottom + i * size_of::<usize>();
+        unsafe { (addr as *const usize).read_volatile() == CANARY }
+    })
+}
+
+/// 栈的最高水位（字节），即曾经被使用过的最大深度
+pub fn high_water_mark() -> usize {
+    let bottom = STACK_BOTTOM.load(Ordering::Relaxed);
+    let top = STACK_TOP.load(Ordering::Relaxed);
+    let mut addr = bottom;
+    while addr < top && unsafe { (addr as *const usize).read_volatile() } == CANARY {
+        addr += size_of::<usize>();
+    }
+    top - addr
+}
+
+/// 通过串口打印栈的使用情况
+pub fn report() {
+    let bottom = STACK_BOTTOM.load(Ordering::Relaxed);
+    let top = STACK_TOP.load(Ordering::Relaxed);
+    println!(
+        "stack:   {:#x}..{:#x}, high water {:#x} / {:#x}",
+        bottom,
+        top,
+        high_water_mark(),
+        top - bottom
+    );
+    println!(
+        "stack:   canary {}, pmp guard {}",
+        if canary_intact() { "ok" } else { "CORRUPTED" },
+        if pmp::is_present() { "on" } else { "off" }
+    );
+}
+
+/// 周期检查金丝雀，被破坏时直接 panic；最高水位上涨时打印一次
+#[embassy_executor::task]
+pub async fn monitor() {
+    let mut last_mark = 0;
+    loop {
+        if !canary_intact() {
+            panic!("stack canary corrupted, stack overflow detected");
+        }
+        let mark = high_water_mark();
+        if mark > last_mark {
+            last_mark = mark;
+            report();
+        }
+        Timer::after(CHECK_PERIOD).await;
+    }
+}
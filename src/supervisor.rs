@@ -0,0 +1,200 @@
+//! S 态运行支持，开启 `supervisor` feature 时代替 M 态的 CLINT 和 mtvec 路径
+//!
+//! 作为 OpenSBI / RustSBI 的 payload 运行：
+//! * 控制台走 SBI DBCN 扩展，固件不支持时回退到旧版 `sbi_console_putchar`；
+//! * 定时器优先直接写 `stimecmp`（Sstc），否则调用 `sbi_set_timer`；
+//! * 陷入由 `stvec` 进入 fast-trap，在 [`fast_handler`] 里按 `scause` 分发。
+
+use core::{
+    arch::asm,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use fast_trap::{FastContext, FastResult};
+use riscv::{
+    interrupt::{Exception, Interrupt, Trap},
+    register::{scause, sepc, stval},
+};
+
+use crate::sbi;
+
+static HAS_SSTC: AtomicBool = AtomicBool::new(false);
+static HAS_DBCN: AtomicBool = AtomicBool::new(false);
+
+/// 探测 Sstc 和 DBCN，必须在安装 `stvec` 之前调用
+pub fn init() {
+    HAS_DBCN.store(sbi::probe_extension(sbi::EID_DBCN), Ordering::Relaxed);
+    HAS_SSTC.store(probe_sstc(), Ordering::Relaxed);
+}
+
+/// 是否可以直接写 `stimecmp`
+pub fn has_sstc() -> bool {
+    HAS_SSTC.load(Ordering::Relaxed)
+}
+
+/// 读 `stimecmp`：未实现或 M 态没有打开 `menvcfg.STCE` 时会触发非法指令，
+/// 这里临时把 `stvec` 指向下面的标签，陷入了就说明不可用
+fn probe_sstc() -> bool {
+    let trapped: usize;
+    unsafe {
+        asm!(
+            "la    {tmp}, 1f",
+            "csrrw {old}, stvec, {tmp}",
+            "li    {trapped}, 0",
+            "csrr  {tmp}, 0x14d",
+            "j     2f",
+            ".align 2",
+            "1:",
+            "li    {trapped}, 1",
+            "2:",
+            "csrw  stvec, {old}",
+            tmp = out(reg) _,
+            old = out(reg) _,
+            trapped = out(reg) trapped,
+        )
+    };
+    trapped == 0
+}
+
+/// 设置下一次定时器中断的绝对时间（`time` 的计数值）
+pub fn set_timer(when_ticks: u64) {
+    if has_sstc() {
+        #[cfg(target_pointer_width = "64")]
+        unsafe {
+            asm!("csrw 0x14d, {}", in(reg) when_ticks)
+        };
+        #[cfg(target_pointer_width = "32")]
+        unsafe {
+            // 先把低位写成最大值，避免中途触发
+            asm!(
+                "csrw 0x14d, {max}",
+                "csrw 0x15d, {hi}",
+                "csrw 0x14d, {lo}",
+                max = in(reg) usize::MAX,
+                hi = in(reg) (when_ticks >> 32) as usize,
+                lo = in(reg) when_ticks as usize,
+            )
+        };
+    } else {
+        sbi::set_timer(when_ticks);
+    }
+}
+
+/// 通过 SBI 输出的控制台
+pub struct SbiConsole;
+
+impl SbiConsole {
+    pub const fn new() -> Self {
+        Self
+    }
+
+    /// 写出一段字节，返回实际写出的字节数
+    pub fn write(&self, buf: &[u8]) -> usize {
+        if HAS_DBCN.load(Ordering::Relaxed) {
+            let ret = sbi::console_write(buf);
+            if ret.is_ok() {
+                ret.value.min(buf.len())
+            } else {
+                0
+            }
+        } else {
+            buf.iter().for_each(|&b| sbi::legacy_console_putchar(b));
+            buf.len()
+        }
+    }
+
+    /// 读取输入，返回实际读到的字节数，不阻塞
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        if HAS_DBCN.load(Ordering::Relaxed) {
+            let ret = sbi::console_read(buf);
+            if ret.is_ok() {
+                ret.value.min(buf.len())
+            } else {
+                0
+            }
+        } else {
+            let mut count = 0;
+            while count < buf.len() {
+                let Some(byte) = sbi::legacy_console_getchar() else {
+                    break;
+                };
+                buf[count] = byte;
+                count += 1;
+            }
+            count
+        }
+    }
+}
+
+impl fmt::Write for SbiConsole {
+    #[inline]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        if HAS_DBCN.load(Ordering::Relaxed) {
+            while !bytes.is_empty() {
+                let ret = sbi::console_write(bytes);
+                if !ret.is_ok() {
+                    return Err(fmt::Error);
+                }
+                bytes = &bytes[ret.value.min(bytes.len())..];
+            }
+        } else {
+            bytes.iter().for_each(|&b| sbi::legacy_console_putchar(b));
+        }
+        Ok(())
+    }
+}
+
+pub extern "C" fn fast_handler(
+    mut ctx: FastContext,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
+    a5: usize,
+    a6: usize,
+    a7: usize,
+) -> FastResult {
+    let epc = sepc::read();
+    ctx.regs().pc = epc;
+
+    let save_regs = |ctx: &mut FastContext| {
+        ctx.regs().a = [ctx.a0(), a1, a2, a3, a4, a5, a6, a7];
+    };
+    let cause = scause::read();
+
+    match cause.cause().try_into() {
+        Ok(cause) => match cause {
+            // Handle STimer
+            Trap::Interrupt(Interrupt::SupervisorTimer) => {
+                crate::time_driver::timer_interrupt_handler();
+
+                save_regs(&mut ctx);
+                ctx.restore()
+            }
+            // Handle SExternal
+            Trap::Interrupt(Interrupt::SupervisorExternal) => {
+                crate::plic::handle_interrupt();
+
+                save_regs(&mut ctx);
+                ctx.restore()
+            }
+            // Handle other traps
+            trap => unsupported_trap(Some(trap)),
+        },
+        Err(err) => {
+            println!("Failed to parse scause: {:?}", err);
+            unsupported_trap(None);
+        }
+    }
+}
+
+pub fn unsupported_trap(trap: Option<Trap<Interrupt, Exception>>) -> ! {
+    println!("-----------------------------");
+    println!("trap:    {trap:?}");
+    println!("sepc:    {:#018x}", sepc::read());
+    println!("stval:   {:#018x}", stval::read());
+    println!("-----------------------------");
+    panic!("Stopped with unsupported trap")
+}
@@ -0,0 +1,227 @@
+//! 任务运行统计，类似 `top`
+//!
+//! 开启 `task-stats` feature 时打开 embassy-executor 的 `trace`，实现它调用的
+//! `_embassy_trace_*` 钩子：每次 poll 前后读 mtime，累计每个任务的 poll 次数、
+//! 总耗时、单次最长耗时和最近一次被唤醒的时间。执行器是单线程的，
+//! 单次 poll 很长的任务会拖住其它所有任务，[`dump`] 打印的表里一眼就能看出来。
+//!
+//! 钩子只给出任务头的地址，任务名由 [`spawn`] 在派生时登记；
+//! 不开 feature 时 [`spawn`] 就是 `Spawner::spawn`。
+
+#[cfg(feature = "task-stats")]
+use core::cell::RefCell;
+
+#[cfg(feature = "task-stats")]
+use critical_section::{Mutex, with};
+use embassy_executor::{SpawnError, SpawnToken, Spawner};
+
+#[cfg(feature = "task-stats")]
+use crate::time_driver;
+
+/// 最多统计的任务数
+#[cfg(feature = "task-stats")]
+const MAX_TASKS: usize = 16;
+
+/// 一个任务的统计
+#[cfg(feature = "task-stats")]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TaskStats {
+    /// 任务头地址的低 32 位，执行器钩子用它区分任务
+    pub id: u32,
+    pub name: &'static str,
+    pub polls: u64,
+    /// 累计 poll 耗时（mtime 计数）
+    pub busy: u64,
+    /// 单次 poll 最长耗时（mtime 计数）
+    pub max_poll: u64,
+    /// 最近一次被唤醒的 mtime，没被唤醒过为 0
+    pub last_wake: u64,
+}
+
+#[cfg(feature = "task-stats")]
+struct State {
+    tasks: [Option<TaskStats>; MAX_TASKS],
+    // 下一个派生的任务的名字
+    next_name: &'static str,
+    // 正在 poll 的任务和开始时间
+    current: Option<(u32, u64)>,
+    // 开始统计的时间
+    since: u64,
+}
+
+#[cfg(feature = "task-stats")]
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    tasks: [None; MAX_TASKS],
+    next_name: "?",
+    current: None,
+    since: 0,
+}));
+
+/// 派生任务并登记它的名字
+pub fn spawn<S>(
+    spawner: &Spawner,
+    name: &'static str,
+    token: SpawnToken<S>,
+) -> Result<(), SpawnError> {
+    #[cfg(feature = "task-stats")]
+    with(|cs| STATE.borrow_ref_mut(cs).next_name = name);
+    #[cfg(not(feature = "task-stats"))]
+    let _ = name;
+    spawner.spawn(token)
+}
+
+#[cfg(feature = "task-stats")]
+impl State {
+    fn task(&mut self, id: u32) -> Option<&mut TaskStats> {
+        self.tasks.iter_mut().flatten().find(|task| task.id == id)
+    }
+}
+
+#[cfg(feature = "task-stats")]
+#[unsafe(no_mangle)]
+fn _embassy_trace_task_new(_executor_id: u32, task_id: u32) {
+    with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        // 占用率从第一个任务派生时算起
+        if state.since == 0 {
+            state.since = time_driver::mtime();
+        }
+        let name = core::mem::replace(&mut state.next_name, "?");
+        // 任务结束后同一个任务池的槽位会被重新派生，沿用原来的表项
+        if let Some(task) = state.task(task_id) {
+            task.name = name;
+            return;
+        }
+        if let Some(slot) = state.tasks.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(TaskStats {
+                id: task_id,
+                name,
+                polls: 0,
+                busy: 0,
+                max_poll: 0,
+                last_wake: 0,
+            });
+        }
+    })
+}
+
+#[cfg(feature = "task-stats")]
+#[unsafe(no_mangle)]
+fn _embassy_trace_task_exec_begin(_executor_id: u32, task_id: u32) {
+    let now = time_driver::mtime();
+    with(|cs| STATE.borrow_ref_mut(cs).current = Some((task_id, now)));
+    #[cfg(feature = "long-poll")]
+    crate::long_poll::poll_begin(now);
+}
+
+#[cfg(feature = "task-stats")]
+#[unsafe(no_mangle)]
+fn _embassy_trace_task_exec_end(_executor_id: u32, task_id: u32) {
+    #[cfg(feature = "long-poll")]
+    crate::long_poll::poll_end();
+    let now = time_driver::mtime();
+    with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        let Some((_, begin)) = state.current.take() else {
+            return;
+        };
+        if let Some(task) = state.task(task_id) {
+            let took = now.saturating_sub(begin);
+            task.polls += 1;
+            task.busy += took;
+            task.max_poll = task.max_poll.max(took);
+        }
+    })
+}
+
+#[cfg(feature = "task-stats")]
+#[unsafe(no_mangle)]
+fn _embassy_trace_task_ready_begin(_executor_id: u32, task_id: u32) {
+    let now = time_driver::mtime();
+    with(|cs| {
+        if let Some(task) = STATE.borrow_ref_mut(cs).task(task_id) {
+            task.last_wake = now;
+        }
+    })
+}
+
+#[cfg(feature = "task-stats")]
+#[unsafe(no_mangle)]
+fn _embassy_trace_executor_idle(_executor_id: u32) {}
+
+/// 正在 poll 的任务和已经 poll 的时间（mtime 计数），在中断里调用
+#[cfg(feature = "task-stats")]
+pub fn current() -> Option<(TaskStats, u64)> {
+    let now = time_driver::mtime();
+    with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        let (id, begin) = state.current?;
+        let task = *state.task(id)?;
+        Some((task, now.saturating_sub(begin)))
+    })
+}
+
+/// 清零统计，重新开始计算占用率
+#[cfg(feature = "task-stats")]
+pub fn reset() {
+    let now = time_driver::mtime();
+    with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        for task in state.tasks.iter_mut().flatten() {
+            task.polls = 0;
+            task.busy = 0;
+            task.max_poll = 0;
+        }
+        state.since = now;
+    })
+}
+
+/// 打印每个任务的统计，占用率从启动或上一次 [`reset`] 算起
+#[cfg(feature = "task-stats")]
+pub fn dump() {
+    let (tasks, since) = with(|cs| {
+        let state = STATE.borrow_ref(cs);
+        (state.tasks, state.since)
+    });
+    let now = time_driver::mtime();
+    let hz = time_driver::mtime_hz().max(1);
+    let us = |ticks: u64| ticks * 1_000_000 / hz;
+    let elapsed = now.saturating_sub(since).max(1);
+    println!(
+        "{:<10} {:<20} {:>8} {:>12} {:>10} {:>6} {:>12}",
+        "id", "name", "polls", "busy(us)", "max(us)", "cpu%", "wake(us ago)"
+    );
+    let mut busy = 0;
+    for task in tasks.iter().flatten() {
+        busy += task.busy;
+        let permille = task.busy * 1000 / elapsed;
+        let wake = match task.last_wake {
+            0 => 0,
+            at => us(now.saturating_sub(at)),
+        };
+        println!(
+            "{:#010x} {:<20} {:>8} {:>12} {:>10} {:>4}.{} {:>12}",
+            task.id,
+            task.name,
+            task.polls,
+            us(task.busy),
+            us(task.max_poll),
+            permille / 10,
+            permille % 10,
+            wake
+        );
+    }
+    let permille = busy * 1000 / elapsed;
+    println!(
+        "busy {}.{}% of {} us",
+        permille / 10,
+        permille % 10,
+        us(elapsed)
+    );
+}
+
+#[cfg(not(feature = "task-stats"))]
+pub fn dump() {
+    println!("task statistics need the `task-stats` feature");
+}
@@ -1,23 +1,22 @@
 //! Embassy time driver implementation using RustSBI's IPI interface
 
-use crate::console::PLATFORM;
-use core::{
-    cell::RefCell,
-    panic,
-    sync::atomic::{AtomicU64, Ordering},
-};
+use core::cell::RefCell;
 use critical_section::{Impl, Mutex, with};
 use embassy_time::TICK_HZ;
 use embassy_time_driver::Driver;
 use embassy_time_queue_utils::Queue;
+// RV32 没有 64 位原子操作，用 portable-atomic 关中断实现
+use portable_atomic::{AtomicU64, Ordering};
 
 // use crate::{CLINT, SifiveClintWrap, get_clint};
+#[cfg(feature = "machine")]
 use crate::CLINT;
 // use rustsbi::Timer;
 
 struct RustSbiCriticalSection;
 critical_section::set_impl!(RustSbiCriticalSection);
 
+#[cfg(feature = "machine")]
 unsafe impl Impl for RustSbiCriticalSection {
     unsafe fn acquire() -> critical_section::RawRestoreState {
         let mstatus = riscv::register::mstatus::read();
@@ -32,13 +31,46 @@ unsafe impl Impl for RustSbiCriticalSection {
     }
 }
 
+// S 态只能操作 sstatus.SIE
+#[cfg(feature = "supervisor")]
+unsafe impl Impl for RustSbiCriticalSection {
+    unsafe fn acquire() -> critical_section::RawRestoreState {
+        let sstatus = riscv::register::sstatus::read();
+        unsafe { riscv::register::sstatus::clear_sie() };
+        // Sstatus 不提供原始值，只保存 SIE 位
+        (sstatus.sie() as usize) << 1
+    }
+
+    unsafe fn release(restore_state: critical_section::RawRestoreState) {
+        if restore_state & (1 << 1) != 0 {
+            unsafe { riscv::register::sstatus::set_sie() };
+        }
+    }
+}
+
 // 引入RustSBI相关类型和接口
 // use crate::{platform::PLATFORM, sbi::ipi::clear_mtime};
 
 // const CLINT_FREQ_HZ: u64 = 51_200_000; // 51.2MHz, stg apb clock
-const CLINT_FREQ_HZ: u64 = 4_000_000; // 51.2MHz, stg apb clock
+// 读不到时钟配置时的默认值：24MHz 晶振 6 分频
+const DEFAULT_CLINT_FREQ_HZ: u64 = 4_000_000;
 // const EMBASSY_TICK_HZ: u64 = 5_120_000; // 5.12MHz from Cargo.toml feature tick-hz-1_000_000
-const FREQ_RATIO: u64 = CLINT_FREQ_HZ / TICK_HZ;
+// CLINT 计数值和 Embassy tick 的比例，init 时按实际的 CLINT 频率计算
+static FREQ_RATIO: AtomicU64 = AtomicU64::new(DEFAULT_CLINT_FREQ_HZ / TICK_HZ);
+
+fn freq_ratio() -> u64 {
+    FREQ_RATIO.load(Ordering::Relaxed)
+}
+
+// S 态 payload 通过 sbi_set_timer 设置的截止时间，和 Embassy 共用同一个 mtimecmp
+#[cfg(feature = "payload")]
+static SUPERVISOR_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+// 当前 poll 的超时时间（mtime 计数），由 long_poll 设置
+#[cfg(feature = "long-poll")]
+static POLL_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+// 比较寄存器里已经装好的值（mtime 计数），poll 超时比它晚时不用重新写
+#[cfg(feature = "long-poll")]
+static ARMED: AtomicU64 = AtomicU64::new(u64::MAX);
 
 struct MachineTimeDriver {
     queue: Mutex<RefCell<Queue>>,
@@ -52,16 +84,27 @@ embassy_time_driver::time_driver_impl!(static DRIVER: MachineTimeDriver = Machin
 
 impl MachineTimeDriver {
     pub fn init(&self) {
+        let clint_hz = match crate::clock::clint_rate() {
+            hz if hz >= TICK_HZ => hz,
+            _ => DEFAULT_CLINT_FREQ_HZ,
+        };
+        FREQ_RATIO.store(clint_hz / TICK_HZ, Ordering::Relaxed);
         // println!("Hello, world!10");
-        let current_time = Self::read_time();
+        let current_time = self.now();
         // println!("Hello, world!11");
         self.next_alarm.store(current_time, Ordering::Relaxed);
         // println!("Hello, world!12");
 
         // 启用机器定时器中断
+        #[cfg(feature = "machine")]
         unsafe {
             riscv::register::mie::set_mtimer();
         }
+        // S 态启用监管者定时器中断
+        #[cfg(feature = "supervisor")]
+        unsafe {
+            riscv::register::sie::set_stimer();
+        }
         // println!("Hello, world!13");
     }
 
@@ -76,59 +119,109 @@ impl MachineTimeDriver {
         // 0
 
         // println!("Hello, world!14");
+        #[cfg(feature = "machine")]
         #[allow(static_mut_refs)]
         unsafe {
             CLINT.read_mtime()
         }
+        // S 态读 time CSR，由固件或硬件映射到 mtime
+        #[cfg(feature = "supervisor")]
+        riscv::register::time::read64()
     }
 
-    fn set_timer(&self, when_ticks: u64) {
+    // `at` 是 Embassy tick，换算成 CLINT 计数值再写比较寄存器
+    fn set_timer(&self, at: u64) {
+        let when_ticks = at.saturating_mul(freq_ratio());
+        #[cfg(feature = "long-poll")]
+        let when_ticks = when_ticks.min(POLL_DEADLINE.load(Ordering::Relaxed));
+        // mtimecmp 取 Embassy 和 S 态两者中较早的一个
+        #[cfg(feature = "payload")]
+        let when_ticks = when_ticks.min(SUPERVISOR_DEADLINE.load(Ordering::Relaxed));
+        #[cfg(feature = "long-poll")]
+        ARMED.store(when_ticks, Ordering::Relaxed);
         // 使用RustSBI的Timer接口设置定时器
         // ipi.set_timer(when_ticks);
         // if let Some(clint) = unsafe { &mut CLINT } {
         // clint.set_msip()
-        #[allow(static_mut_refs)]
-        unsafe {
-            CLINT.write_mtimecmp(0, when_ticks)
-        };
-        unsafe {
-            riscv::register::mip::clear_stimer();
-        }
-        // Enable machine timer interrupt.
-        unsafe {
-            riscv::register::mie::set_mtimer();
+        #[cfg(feature = "machine")]
+        {
+            #[allow(static_mut_refs)]
+            unsafe {
+                CLINT.write_mtimecmp(0, when_ticks)
+            };
+            // payload 模式下 STIP 由 S 态定时器注入，不能在这里清掉
+            #[cfg(not(feature = "payload"))]
+            unsafe {
+                riscv::register::mip::clear_stimer();
+            }
+            // Enable machine timer interrupt.
+            unsafe {
+                riscv::register::mie::set_mtimer();
+            }
         }
         // }
+        #[cfg(feature = "supervisor")]
+        {
+            crate::supervisor::set_timer(when_ticks);
+            unsafe {
+                riscv::register::sie::set_stimer();
+            }
+        }
     }
 
     pub fn handle_timer_interrupt(&self) {
         // clear_mtime();
         // if let Some(clint) = unsafe { &mut CLINT } {
+        #[cfg(feature = "machine")]
         #[allow(static_mut_refs)]
         unsafe {
             CLINT.write_mtimecmp(0, u64::MAX)
         };
         // }
+        #[cfg(feature = "supervisor")]
+        crate::supervisor::set_timer(u64::MAX);
         with(|cs| {
             let now = Self::read_time();
+            #[cfg(feature = "long-poll")]
+            if now >= POLL_DEADLINE.load(Ordering::Relaxed) {
+                POLL_DEADLINE.store(u64::MAX, Ordering::Relaxed);
+                crate::long_poll::report();
+            }
+            // S 态的截止时间到了就注入 STIP，由 payload 自己处理
+            #[cfg(feature = "payload")]
+            if now >= SUPERVISOR_DEADLINE.load(Ordering::Relaxed) {
+                SUPERVISOR_DEADLINE.store(u64::MAX, Ordering::Relaxed);
+                unsafe { riscv::register::mip::set_stimer() };
+            }
             let mut queue = self.queue.borrow_ref_mut(cs);
-            let next_alarm = queue.next_expiration(now);
+            let next_alarm = queue.next_expiration(now / freq_ratio());
 
             if next_alarm != u64::MAX {
                 self.set_timer(next_alarm);
                 self.next_alarm.store(next_alarm, Ordering::Relaxed);
             } else {
+                // 还可能有 S 态的截止时间或者 poll 超时需要继续等待
+                #[cfg(any(feature = "payload", feature = "long-poll"))]
+                self.set_timer(u64::MAX);
                 self.next_alarm.store(u64::MAX, Ordering::Relaxed);
             }
         })
     }
+
+    /// 处理 S 态的 `sbi_set_timer`：清除 STIP 并记录新的截止时间
+    #[cfg(feature = "payload")]
+    pub fn set_supervisor_timer(&self, deadline: u64) {
+        unsafe { riscv::register::mip::clear_stimer() };
+        SUPERVISOR_DEADLINE.store(deadline, Ordering::Relaxed);
+        self.set_timer(self.next_alarm.load(Ordering::Relaxed));
+    }
 }
 
 impl Driver for MachineTimeDriver {
     fn now(&self) -> u64 {
         let current_ticks = Self::read_time();
 
-        current_ticks / FREQ_RATIO
+        current_ticks / freq_ratio()
     }
 
     fn schedule_wake(&self, at: u64, waker: &core::task::Waker) {
@@ -136,7 +229,7 @@ impl Driver for MachineTimeDriver {
             let mut queue = self.queue.borrow_ref_mut(cs);
 
             if queue.schedule_wake(at, waker) {
-                let now = Self::read_time();
+                let now = self.now();
                 let next = queue.next_expiration(now);
                 self.set_timer(next);
                 self.next_alarm.store(at, Ordering::Relaxed);
@@ -155,22 +248,53 @@ pub fn init() {
     // info!("mip = {:x}", riscv::register::mip::read().bits());
 }
 
+/// 设置当前 poll 的超时时间，到时定时器中断调用 `long_poll::report`，`u64::MAX` 取消
+///
+/// 每次 poll 开始和结束都会调用，只有比已经装好的比较值更早时才写 mtimecmp；
+/// 取消或者推迟时留着旧的比较值，到时的中断按最新的超时时间判断后重新装上。
+#[cfg(feature = "long-poll")]
+pub fn set_poll_deadline(deadline: u64) {
+    POLL_DEADLINE.store(deadline, Ordering::Relaxed);
+    // 中断可能在这之间重新装比较值，它已经读到了新的超时时间，这里再比较一次就不会漏掉
+    if deadline < ARMED.load(Ordering::Relaxed) {
+        with(|_| DRIVER.set_timer(DRIVER.next_alarm.load(Ordering::Relaxed)));
+    }
+}
+
+/// 原始的 mtime 计数值，S 态读 `time` CSR
+pub fn mtime() -> u64 {
+    MachineTimeDriver::read_time()
+}
+
+/// mtime 的计数频率
+pub fn mtime_hz() -> u64 {
+    freq_ratio() * TICK_HZ
+}
+
 pub fn timer_interrupt_handler() {
     DRIVER.handle_timer_interrupt();
 }
 
+#[cfg(feature = "payload")]
+pub fn set_supervisor_timer(deadline: u64) {
+    with(|_| DRIVER.set_supervisor_timer(deadline));
+}
+
 pub fn is_timer_interrupt_pending() -> bool {
-    use riscv::register::mip;
-    mip::read().mtimer()
+    #[cfg(feature = "machine")]
+    return riscv::register::mip::read().mtimer();
+    #[cfg(feature = "supervisor")]
+    return riscv::register::sip::read().stimer();
 }
 
-// #[cfg(feature = "defmt")]
-// impl defmt::Format for MachineTimeDriver {
-//     fn format(&self, f: defmt::Formatter) {
-//         defmt::write!(
-//             f,
-//             "MachineTimeDriver {{ next_alarm: {} }}",
-//             self.next_alarm.load(Ordering::Relaxed)
-//         )
-//     }
-// }
+#[cfg(feature = "defmt")]
+impl defmt::Format for MachineTimeDriver {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "MachineTimeDriver {{ next_alarm: {}, freq_ratio: {} }}",
+            self.next_alarm.load(Ordering::Relaxed),
+            freq_ratio()
+        )
+    }
+}
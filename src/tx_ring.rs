@@ -0,0 +1,239 @@
+//! 控制台的发送缓冲区
+//!
+//! 多个写入方（任务和嵌套的中断）不加锁地往里放，同一时间只有一个读出方取出来发给 UART。
+//! 只用到原子操作，下面的测试直接在主机上运行（`scripts/host-test.sh`）。
+
+use core::{cell::UnsafeCell, sync::atomic::Ordering};
+
+use portable_atomic::{AtomicBool, AtomicUsize};
+
+// 下面三个都是单调递增（回绕）的计数，取模后才是下标：
+// tail <= commit <= reserve，tail 之前的已经发出，commit 之前的已经写完，reserve 之前的已被占用。
+// Overwrite 会把 tail 推到 commit 前面，这时读出方没有数据可发。
+/// 大小为 `N` 的发送缓冲区，`N` 必须是 2 的幂
+pub struct TxRing<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    reserve: AtomicUsize,
+    commit: AtomicUsize,
+    tail: AtomicUsize,
+    // 正在拷贝的写入方个数，最后一个离开的负责推进 commit
+    writers: AtomicUsize,
+    // 同一时间只有一个读出方，被打断的读出方回来后会接着发
+    reading: AtomicBool,
+}
+
+// 写入方只碰自己占用的那段，读出方只读 commit 之前的，除 Overwrite 外两边不会重叠
+unsafe impl<const N: usize> Sync for TxRing<N> {}
+
+impl<const N: usize> TxRing<N> {
+    pub const fn new() -> Self {
+        // 计数回绕时取模的结果要连续
+        const { assert!(N.is_power_of_two()) };
+        Self {
+            buf: UnsafeCell::new([0; N]),
+            reserve: AtomicUsize::new(0),
+            commit: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            writers: AtomicUsize::new(0),
+            reading: AtomicBool::new(false),
+        }
+    }
+
+    /// 还能放进去的字节数
+    pub fn free(&self) -> usize {
+        let used = self
+            .reserve
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.tail.load(Ordering::Acquire));
+        N - used
+    }
+
+    /// 放进尽可能多的数据，返回放进去的字节数和被覆盖掉的旧数据字节数；
+    /// `overwrite` 时空间不够就推进 tail
+    pub fn push(&self, bytes: &[u8], overwrite: bool) -> (usize, usize) {
+        self.writers.fetch_add(1, Ordering::Acquire);
+        let mut start = self.reserve.load(Ordering::Relaxed);
+        let (len, free) = loop {
+            let free = N - start.wrapping_sub(self.tail.load(Ordering::Acquire));
+            let len = if overwrite {
+                bytes.len().min(N)
+            } else {
+                bytes.len().min(free)
+            };
+            match self.reserve.compare_exchange_weak(
+                start,
+                start.wrapping_add(len),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break (len, free),
+                Err(current) => start = current,
+            }
+        };
+        let discarded = if len > free {
+            // 新的 tail 是 reserve - N，只留最后 N 字节
+            advance(&self.tail, start.wrapping_add(len).wrapping_sub(N))
+        } else {
+            0
+        };
+        let index = start % N;
+        let first = len.min(N - index);
+        let buf = self.buf.get() as *mut u8;
+        unsafe {
+            core::ptr::copy_nonoverlapping(bytes.as_ptr(), buf.add(index), first);
+            core::ptr::copy_nonoverlapping(bytes[first..].as_ptr(), buf, len - first);
+        }
+        // 单核上中断嵌套是后进先出的：减到 0 之后再开始的写入方在我们继续之前就已经写完，
+        // 此时读到的 reserve 之前不会有还在拷贝的数据
+        if self.writers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            advance(&self.commit, self.reserve.load(Ordering::Acquire));
+        }
+        (len, discarded)
+    }
+
+    /// 成为读出方，已经有读出方（被打断的那个）时返回 `false`
+    pub fn acquire_reader(&self) -> bool {
+        !self.reading.swap(true, Ordering::Acquire)
+    }
+
+    /// 放弃读出方，panic 时也用来清掉再也不会回来的读出方
+    pub fn release_reader(&self) {
+        self.reading.store(false, Ordering::Release);
+    }
+
+    /// 从 tail 开始拷出一段连续的已写完数据，返回拷贝时的 tail 和长度，没有数据时返回 `None`
+    ///
+    /// 只取到缓冲区末尾，回绕的部分下次再取。只能由读出方调用。
+    pub fn peek(&self, out: &mut [u8]) -> Option<(usize, usize)> {
+        loop {
+            let tail = self.tail.load(Ordering::Acquire);
+            let pending = self.commit.load(Ordering::Acquire).wrapping_sub(tail);
+            if pending as isize <= 0 {
+                return None;
+            }
+            let start = tail % N;
+            let len = pending.min(N - start).min(out.len());
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    (self.buf.get() as *const u8).add(start),
+                    out.as_mut_ptr(),
+                    len,
+                )
+            };
+            // Overwrite 的写入方先推进 tail 再覆盖数据，tail 没变说明拷出来的是完整的
+            if self.tail.load(Ordering::Acquire) == tail {
+                return Some((tail, len));
+            }
+        }
+    }
+
+    /// [`TxRing::peek`] 拷出的数据已经发出了 `count` 字节
+    pub fn consume(&self, tail: usize, count: usize) {
+        // 发送期间写入方可能又推进了 tail，这时以它为准
+        let _ = self.tail.compare_exchange(
+            tail,
+            tail.wrapping_add(count),
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+// 把计数推进到 `to`，已经在它后面时不动，返回推进的距离
+fn advance(counter: &AtomicUsize, to: usize) -> usize {
+    let mut current = counter.load(Ordering::Relaxed);
+    loop {
+        let distance = to.wrapping_sub(current);
+        if distance as isize <= 0 {
+            return 0;
+        }
+        match counter.compare_exchange_weak(current, to, Ordering::AcqRel, Ordering::Relaxed) {
+            Ok(_) => return distance,
+            Err(value) => current = value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    // 读出方把能取的都取出来
+    fn drain<const N: usize>(ring: &TxRing<N>) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut chunk = [0; 4];
+        assert!(ring.acquire_reader());
+        while let Some((tail, len)) = ring.peek(&mut chunk) {
+            out.extend_from_slice(&chunk[..len]);
+            ring.consume(tail, len);
+        }
+        ring.release_reader();
+        out
+    }
+
+    #[test]
+    fn wraps_around() {
+        let ring = TxRing::<16>::new();
+        assert_eq!(ring.push(b"0123456789", false), (10, 0));
+        assert_eq!(drain(&ring), b"0123456789");
+        // 从下标 10 开始，跨过缓冲区末尾
+        assert_eq!(ring.push(b"abcdefghij", false), (10, 0));
+        let mut chunk = [0; 16];
+        assert_eq!(ring.peek(&mut chunk), Some((10, 6)));
+        assert_eq!(&chunk[..6], b"abcdef");
+        ring.consume(10, 6);
+        assert_eq!(ring.peek(&mut chunk), Some((16, 4)));
+        assert_eq!(&chunk[..4], b"ghij");
+        ring.consume(16, 4);
+        assert_eq!(ring.peek(&mut chunk), None);
+        assert_eq!(ring.free(), 16);
+    }
+
+    #[test]
+    fn drop_keeps_old_data() {
+        let ring = TxRing::<16>::new();
+        assert_eq!(ring.push(b"0123456789abcdefXYZ", false), (16, 0));
+        assert_eq!(ring.free(), 0);
+        assert_eq!(ring.push(b"XYZ", false), (0, 0));
+        assert_eq!(drain(&ring), b"0123456789abcdef");
+    }
+
+    #[test]
+    fn overwrite_discards_oldest() {
+        let ring = TxRing::<16>::new();
+        assert_eq!(ring.push(b"0123456789", true), (10, 0));
+        assert_eq!(ring.push(b"abcdefghij", true), (10, 4));
+        assert_eq!(drain(&ring), b"456789abcdefghij");
+        // 一次最多放进 N 字节，剩下的由调用方接着放，把前面的覆盖掉
+        assert_eq!(ring.push(b"0123456789abcdefXYZ", true), (16, 0));
+        assert_eq!(ring.push(b"XYZ", true), (3, 3));
+        assert_eq!(drain(&ring), b"3456789abcdefXYZ");
+    }
+
+    #[test]
+    fn overwrite_while_sending() {
+        let ring = TxRing::<16>::new();
+        ring.push(b"0123456789abcdef", true);
+        assert!(ring.acquire_reader());
+        let mut chunk = [0; 4];
+        let (tail, len) = ring.peek(&mut chunk).unwrap();
+        assert_eq!(&chunk[..len], b"0123");
+        // 读出方发送期间被打断，写入方覆盖了它正在发的数据
+        assert_eq!(ring.push(b"ABCDEFGH", true), (8, 8));
+        ring.consume(tail, len);
+        ring.release_reader();
+        // 已经被覆盖的部分不会再发，tail 也没有被读出方拉回去
+        assert_eq!(drain(&ring), b"89abcdefABCDEFGH");
+    }
+
+    #[test]
+    fn one_reader_at_a_time() {
+        let ring = TxRing::<16>::new();
+        assert!(ring.acquire_reader());
+        assert!(!ring.acquire_reader());
+        ring.release_reader();
+        assert!(ring.acquire_reader());
+    }
+}
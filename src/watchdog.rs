@@ -0,0 +1,328 @@
+//! 硬件看门狗和喂狗任务
+//!
+//! 支持 JH7110 的 StarFive 看门狗（和 ARM SP805 类似，计数到 0 先产生中断，
+//! 中断没清除时第二次到 0 才复位，所以装载值取超时时间的一半）；打开 `sifive-wdt`
+//! feature 时还支持 `sifive,wdt`（FU540 等 AON 块里的 wdog）。
+//!
+//! `watchdog` feature 下的 [`feeder`] 任务只有在所有通过 [`register`] 登记的任务都报到过
+//! 之后才喂狗，执行器卡死或者某个任务卡住都会让看门狗复位。复位原因记在 `.noinit` 段里，
+//! 热复位后内存内容还在，下次启动由 [`reset_reason`] 读出。
+
+use embassy_time::Duration;
+#[cfg(feature = "watchdog")]
+use embassy_time::Timer;
+#[cfg(feature = "watchdog")]
+use portable_atomic::{AtomicU32, Ordering};
+
+use crate::clock::{
+    self, Crg, ResetTimeout, SYSCLK_WDT_APB, SYSCLK_WDT_CORE, SYSRST_WDT_APB, SYSRST_WDT_CORE,
+};
+
+const JH7110_WDT_BASE: usize = 0x1307_0000;
+
+const JH7110_LOAD: usize = 0x000;
+const JH7110_CONTROL: usize = 0x008;
+const JH7110_INTCLR: usize = 0x00c;
+const JH7110_LOCK: usize = 0xc00;
+const JH7110_UNLOCK_KEY: u32 = 0x1acc_e551;
+const JH7110_CONTROL_EN: u32 = 1 << 0;
+const JH7110_CONTROL_RESEN: u32 = 1 << 1;
+
+#[cfg(feature = "sifive-wdt")]
+const SIFIVE_WDOGCFG: usize = 0x00;
+#[cfg(feature = "sifive-wdt")]
+const SIFIVE_WDOGCOUNT: usize = 0x08;
+#[cfg(feature = "sifive-wdt")]
+const SIFIVE_WDOGFEED: usize = 0x18;
+#[cfg(feature = "sifive-wdt")]
+const SIFIVE_WDOGKEY: usize = 0x1c;
+#[cfg(feature = "sifive-wdt")]
+const SIFIVE_WDOGCMP0: usize = 0x20;
+#[cfg(feature = "sifive-wdt")]
+const SIFIVE_KEY: u32 = 0x0051_f15e;
+#[cfg(feature = "sifive-wdt")]
+const SIFIVE_FEED: u32 = 0x0d09_f00d;
+#[cfg(feature = "sifive-wdt")]
+const SIFIVE_CFG_SCALE_MASK: u32 = 0xf;
+#[cfg(feature = "sifive-wdt")]
+const SIFIVE_CFG_RSTEN: u32 = 1 << 8;
+#[cfg(feature = "sifive-wdt")]
+const SIFIVE_CFG_ZEROCMP: u32 = 1 << 9;
+#[cfg(feature = "sifive-wdt")]
+const SIFIVE_CFG_ENALWAYS: u32 = 1 << 12;
+
+// 读不到时钟频率时按晶振计算
+const DEFAULT_CLK_HZ: u64 = 24_000_000;
+
+/// 看门狗的种类
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Variant {
+    Jh7110,
+    #[cfg(feature = "sifive-wdt")]
+    Sifive,
+}
+
+pub struct Watchdog {
+    variant: Variant,
+    base: usize,
+    clk_hz: u64,
+    // 装载值，JH7110 为 LOAD，SiFive 为 wdogcmp0
+    count: u32,
+}
+
+impl Watchdog {
+    /// JH7110 的看门狗，打开时钟并释放复位
+    pub fn jh7110() -> Result<Self, ResetTimeout> {
+        clock::bring_up(
+            Crg::Sys,
+            &[SYSCLK_WDT_APB, SYSCLK_WDT_CORE],
+            &[SYSRST_WDT_APB, SYSRST_WDT_CORE],
+        )?;
+        let clk_hz = clock::sys_rate(SYSCLK_WDT_CORE)
+            .filter(|&hz| hz != 0)
+            .unwrap_or(DEFAULT_CLK_HZ);
+        Ok(Self::new(Variant::Jh7110, JH7110_WDT_BASE, clk_hz))
+    }
+
+    /// `sifive,wdt`，`clk_hz` 一般为 AON 的 32768 Hz 低速时钟
+    #[cfg(feature = "sifive-wdt")]
+    pub fn sifive(base: usize, clk_hz: u64) -> Self {
+        Self::new(Variant::Sifive, base, clk_hz)
+    }
+
+    const fn new(variant: Variant, base: usize, clk_hz: u64) -> Self {
+        Self {
+            variant,
+            base,
+            clk_hz,
+            count: 0,
+        }
+    }
+
+    #[cfg(feature = "sifive-wdt")]
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) }
+    }
+
+    // 每次写寄存器前都要先解锁
+    fn unlocked(&self, f: impl FnOnce(&Self)) {
+        match self.variant {
+            Variant::Jh7110 => {
+                self.write(JH7110_LOCK, JH7110_UNLOCK_KEY);
+                f(self);
+                self.write(JH7110_LOCK, !JH7110_UNLOCK_KEY);
+            }
+            #[cfg(feature = "sifive-wdt")]
+            Variant::Sifive => f(self),
+        }
+    }
+
+    #[cfg(feature = "sifive-wdt")]
+    fn sifive_write(&self, offset: usize, value: u32) {
+        self.write(SIFIVE_WDOGKEY, SIFIVE_KEY);
+        self.write(offset, value);
+    }
+
+    /// 按 `timeout` 启动看门狗，之后必须定期调用 [`Watchdog::feed`]
+    pub fn start(&mut self, timeout: Duration) {
+        let ticks = timeout.as_micros() * self.clk_hz / 1_000_000;
+        match self.variant {
+            Variant::Jh7110 => {
+                self.count = (ticks / 2).clamp(1, u32::MAX as u64) as u32;
+                self.unlocked(|wdt| {
+                    wdt.write(JH7110_LOAD, wdt.count);
+                    wdt.write(JH7110_INTCLR, 1);
+                    wdt.write(JH7110_CONTROL, JH7110_CONTROL_EN | JH7110_CONTROL_RESEN);
+                });
+            }
+            #[cfg(feature = "sifive-wdt")]
+            Variant::Sifive => {
+                // 比较器只有 16 位，放不下就加大 scale
+                let mut scale = 0;
+                while scale < SIFIVE_CFG_SCALE_MASK && (ticks >> scale) > u16::MAX as u64 {
+                    scale += 1;
+                }
+                self.count = (ticks >> scale).clamp(1, u16::MAX as u64) as u32;
+                self.sifive_write(SIFIVE_WDOGCMP0, self.count);
+                self.sifive_write(SIFIVE_WDOGCOUNT, 0);
+                self.sifive_write(
+                    SIFIVE_WDOGCFG,
+                    scale | SIFIVE_CFG_RSTEN | SIFIVE_CFG_ZEROCMP | SIFIVE_CFG_ENALWAYS,
+                );
+            }
+        }
+        record(ResetReason::Watchdog);
+    }
+
+    /// 喂狗
+    #[cfg(feature = "watchdog")]
+    pub fn feed(&mut self) {
+        match self.variant {
+            Variant::Jh7110 => self.unlocked(|wdt| {
+                wdt.write(JH7110_INTCLR, 1);
+                wdt.write(JH7110_LOAD, wdt.count);
+            }),
+            #[cfg(feature = "sifive-wdt")]
+            Variant::Sifive => self.sifive_write(SIFIVE_WDOGFEED, SIFIVE_FEED),
+        }
+    }
+
+    /// 停止看门狗，panic 后进入监视器时用，免得调试时被复位
+    #[cfg(all(feature = "watchdog", feature = "monitor"))]
+    pub fn stop(&mut self) {
+        match self.variant {
+            Variant::Jh7110 => self.unlocked(|wdt| wdt.write(JH7110_CONTROL, 0)),
+            #[cfg(feature = "sifive-wdt")]
+            Variant::Sifive => {
+                let cfg = self.read(SIFIVE_WDOGCFG) & !(SIFIVE_CFG_RSTEN | SIFIVE_CFG_ENALWAYS);
+                self.sifive_write(SIFIVE_WDOGCFG, cfg);
+            }
+        }
+        record(ResetReason::Unknown);
+    }
+}
+
+// 登记过的任务和本轮已经报到的任务，每个任务占一位
+#[cfg(feature = "watchdog")]
+static REGISTERED: AtomicU32 = AtomicU32::new(0);
+#[cfg(feature = "watchdog")]
+static CHECKED_IN: AtomicU32 = AtomicU32::new(0);
+
+/// 登记到喂狗任务上的任务，需要在每个喂狗周期内至少调用一次 [`CheckIn::check_in`]
+#[cfg(feature = "watchdog")]
+pub struct CheckIn(u32);
+
+#[cfg(feature = "watchdog")]
+impl CheckIn {
+    pub fn check_in(&self) {
+        CHECKED_IN.fetch_or(self.0, Ordering::Relaxed);
+    }
+}
+
+/// 登记一个需要报到的任务，最多 32 个
+#[cfg(feature = "watchdog")]
+pub fn register() -> Option<CheckIn> {
+    critical_section::with(|_| {
+        let registered = REGISTERED.load(Ordering::Relaxed);
+        let bit = (!registered).trailing_zeros();
+        if bit >= 32 {
+            return None;
+        }
+        REGISTERED.store(registered | (1 << bit), Ordering::Relaxed);
+        Some(CheckIn(1 << bit))
+    })
+}
+
+/// 喂狗任务，`period` 应小于超时时间的一半
+#[cfg(feature = "watchdog")]
+#[embassy_executor::task]
+pub async fn feeder(mut watchdog: Watchdog, period: Duration) {
+    let mut starving = 0;
+    loop {
+        Timer::after(period).await;
+        let registered = REGISTERED.load(Ordering::Relaxed);
+        let missing = registered & !CHECKED_IN.load(Ordering::Relaxed);
+        if missing == 0 {
+            CHECKED_IN.store(0, Ordering::Relaxed);
+            watchdog.feed();
+            starving = 0;
+        } else if missing != starving {
+            // 不喂狗，等它复位
+            println!("watchdog: tasks {:#x} missed check-in", missing);
+            starving = missing;
+        }
+    }
+}
+
+/// 上一次复位的原因
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ResetReason {
+    /// 上电，`.noinit` 里没有有效记录
+    PowerOn = 0,
+    /// 看门狗运行期间复位，通常是看门狗超时
+    Watchdog = 1,
+    /// panic 之后复位
+    Panic = 2,
+    /// 有记录但看门狗没在运行，例如按了复位键
+    Unknown = 3,
+    /// 通过 [`system_reset`] 主动复位
+    Requested = 4,
+}
+
+const RECORD_MAGIC: u32 = 0x5744_5447;
+
+#[unsafe(link_section = ".noinit")]
+static mut RECORD: [u32; 2] = [0; 2];
+static mut LAST_REASON: ResetReason = ResetReason::PowerOn;
+
+fn record(reason: ResetReason) {
+    unsafe {
+        RECORD[1] = reason as u32;
+        RECORD[0] = RECORD_MAGIC;
+    }
+}
+
+/// 读出上一次的复位原因，并把记录改成“未知”，必须在启动看门狗之前调用
+pub fn init() {
+    let last = unsafe {
+        match RECORD[0] {
+            RECORD_MAGIC => match RECORD[1] {
+                1 => ResetReason::Watchdog,
+                2 => ResetReason::Panic,
+                4 => ResetReason::Requested,
+                _ => ResetReason::Unknown,
+            },
+            _ => ResetReason::PowerOn,
+        }
+    };
+    unsafe { LAST_REASON = last };
+    record(ResetReason::Unknown);
+}
+
+/// 上一次复位的原因
+pub fn reset_reason() -> ResetReason {
+    unsafe { LAST_REASON }
+}
+
+/// panic 时调用，让下次启动能看到
+pub fn record_panic() {
+    record(ResetReason::Panic);
+}
+
+// QEMU virt 的 sifive,test 设备，写入 0x7777 复位
+#[cfg(feature = "qemu")]
+const QEMU_TEST_BASE: usize = 0x10_0000;
+#[cfg(feature = "qemu")]
+const QEMU_TEST_RESET: u32 = 0x7777;
+
+/// 复位整个系统
+///
+/// S 态先请求固件的 SRST 扩展；M 态（或固件不支持时）让看门狗以最短超时复位，
+/// QEMU 上写 test 设备。
+pub fn system_reset() -> ! {
+    crate::console::flush();
+    // 复位随时可能发生（看门狗只等 1 µs），原因要先记下
+    record(ResetReason::Requested);
+    #[cfg(feature = "qemu")]
+    unsafe {
+        (QEMU_TEST_BASE as *mut u32).write_volatile(QEMU_TEST_RESET)
+    };
+    #[cfg(feature = "supervisor")]
+    crate::sbi::system_reset(
+        crate::sbi::RESET_TYPE_COLD_REBOOT,
+        crate::sbi::RESET_REASON_NONE,
+    );
+    #[cfg(not(feature = "qemu"))]
+    if let Ok(mut watchdog) = Watchdog::jh7110() {
+        watchdog.start(Duration::from_micros(1));
+    }
+    loop {
+        core::hint::spin_loop();
+    }
+}